@@ -0,0 +1,125 @@
+use cpal;
+use hound;
+
+/// The format every recording is written in: 16-bit signed PCM at the device's sample rate and
+/// channel count, whatever sample type the device delivers.
+pub fn output_spec(format: &cpal::Format) -> hound::WavSpec {
+    hound::WavSpec {
+        channels: format.channels,
+        sample_rate: format.sample_rate.0,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    }
+}
+
+/// Re-centres an unsigned sample, which is exact: 0 becomes -32768 and 32768 becomes 0.
+fn u16_to_i16(sample: u16) -> i16 {
+    (sample as i32 - 32768) as i16
+}
+
+/// Scales a float sample to full scale, clamping anything outside [-1.0, 1.0] and rounding
+/// rather than truncating, so quiet signals aren't biased towards zero.
+fn f32_to_i16(sample: f32) -> i16 {
+    if sample.is_nan() {
+        return 0;
+    }
+    (sample.max(-1.0).min(1.0) * i16::max_value() as f32).round() as i16
+}
+
+/// A sample type a device can deliver.
+trait InputSample: Copy {
+    fn to_recording(self) -> i16;
+}
+
+impl InputSample for u16 {
+    fn to_recording(self) -> i16 {
+        u16_to_i16(self)
+    }
+}
+
+impl InputSample for i16 {
+    fn to_recording(self) -> i16 {
+        self
+    }
+}
+
+impl InputSample for f32 {
+    fn to_recording(self) -> i16 {
+        f32_to_i16(self)
+    }
+}
+
+fn extend<S: InputSample>(input: &[S], output: &mut Vec<i16>) {
+    output.extend(input.iter().map(|&sample| sample.to_recording()));
+}
+
+/// Appends an input buffer's samples to `output` in the recording format.
+pub fn normalize(buffer: &cpal::UnknownTypeInputBuffer, output: &mut Vec<i16>) {
+    match *buffer {
+        cpal::UnknownTypeInputBuffer::U16(ref buffer) => extend(buffer, output),
+        cpal::UnknownTypeInputBuffer::I16(ref buffer) => extend(buffer, output),
+        cpal::UnknownTypeInputBuffer::F32(ref buffer) => extend(buffer, output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized<S: InputSample>(input: &[S]) -> Vec<i16> {
+        let mut output = vec![7];
+        extend(input, &mut output);
+        assert_eq!(output[0], 7, "normalizing should append");
+        output.split_off(1)
+    }
+
+    #[test]
+    fn output_is_always_16_bit_int() {
+        for &data_type in &[cpal::SampleFormat::U16, cpal::SampleFormat::I16, cpal::SampleFormat::F32] {
+            let spec = output_spec(&cpal::Format {
+                channels: 2,
+                sample_rate: cpal::SampleRate(44_100),
+                data_type: data_type,
+            });
+            assert_eq!(spec.bits_per_sample, 16);
+            assert_eq!(spec.sample_format, hound::SampleFormat::Int);
+            assert_eq!(spec.channels, 2);
+            assert_eq!(spec.sample_rate, 44_100);
+        }
+    }
+
+    #[test]
+    fn i16_passes_through() {
+        let input = [i16::min_value(), -1, 0, 1, i16::max_value()];
+        assert_eq!(normalized(&input), input.to_vec());
+    }
+
+    #[test]
+    fn u16_is_recentred() {
+        assert_eq!(u16_to_i16(0), -32768);
+        assert_eq!(u16_to_i16(32768), 0);
+        assert_eq!(u16_to_i16(65535), 32767);
+        assert_eq!(normalized(&[0u16, 32767, 32768, 32769, 65535]), vec![-32768, -1, 0, 1, 32767]);
+    }
+
+    #[test]
+    fn f32_is_scaled_clamped_and_rounded() {
+        assert_eq!(f32_to_i16(0.0), 0);
+        assert_eq!(f32_to_i16(1.0), 32767);
+        assert_eq!(f32_to_i16(-1.0), -32767);
+        assert_eq!(f32_to_i16(1.5), 32767);
+        assert_eq!(f32_to_i16(-1.5), -32767);
+        assert_eq!(f32_to_i16(::std::f32::INFINITY), 32767);
+        assert_eq!(f32_to_i16(::std::f32::NEG_INFINITY), -32767);
+        assert_eq!(f32_to_i16(::std::f32::NAN), 0);
+
+        // Rounded to the nearest step, not truncated towards zero.
+        let step = 1.0 / 32767.0;
+        assert_eq!(f32_to_i16(0.6 * step), 1);
+        assert_eq!(f32_to_i16(-0.6 * step), -1);
+        assert_eq!(f32_to_i16(0.4 * step), 0);
+        assert_eq!(f32_to_i16(100.6 * step), 101);
+
+        assert_eq!(normalized(&[0.5f32, -0.5, 2.0, ::std::f32::NAN]), vec![16384, -16384, 32767, 0]);
+    }
+}
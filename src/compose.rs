@@ -0,0 +1,328 @@
+use hound;
+use serde_json;
+
+use std::f32::consts::FRAC_PI_2;
+use std::fs::File;
+use std::path::Path;
+
+use convert;
+use resample;
+
+/// One line of a composition, synthesized with its own voice settings. Anything left unset comes
+/// from the config, environment and defaults, as for a single synthesis.
+#[derive(Deserialize)]
+pub struct Segment {
+    #[serde(rename = "text")]
+    pub text: String,
+
+    /// A named preset from the config file, which the settings below override.
+    #[serde(default)]
+    #[serde(rename = "preset")]
+    pub preset: Option<String>,
+
+    #[serde(default)]
+    #[serde(rename = "voice")]
+    pub voice: Option<String>,
+
+    #[serde(default)]
+    #[serde(rename = "language")]
+    pub language: Option<String>,
+
+    #[serde(default)]
+    #[serde(rename = "gender")]
+    pub gender: Option<String>,
+
+    #[serde(default)]
+    #[serde(rename = "rate")]
+    pub rate: Option<f32>,
+
+    #[serde(default)]
+    #[serde(rename = "pitch")]
+    pub pitch: Option<f32>,
+
+    #[serde(default)]
+    #[serde(rename = "gain")]
+    pub gain: Option<f32>,
+
+    /// Seconds of silence before this segment.
+    #[serde(default)]
+    #[serde(rename = "gap")]
+    pub gap: Option<f32>,
+
+    /// Seconds this segment overlaps the end of the previous one, fading between them.
+    #[serde(default)]
+    #[serde(rename = "crossfade")]
+    pub crossfade: Option<f32>,
+}
+
+/// An ordered list of segments, read from JSON like
+/// `{"segments": [{"text": "Hello", "voice": "en-US-Wavenet-C"}, {"text": "Hi", "gap": 0.3}]}`.
+#[derive(Deserialize)]
+pub struct Script {
+    #[serde(rename = "segments")]
+    pub segments: Vec<Segment>,
+}
+
+pub enum Join {
+    Gap(f32),
+    Crossfade(f32),
+}
+
+impl Segment {
+    pub fn join(&self) -> Result<Join, String> {
+        match (self.gap, self.crossfade) {
+            (Some(_), Some(_)) => Err(format!("Segment \"{}\" can't have both a gap and a crossfade", self.text)),
+            (Some(gap), None) if !(gap >= 0.0 && gap.is_finite()) => Err(format!("Segment \"{}\" gap must be zero or more seconds, not {}", self.text, gap)),
+            (None, Some(crossfade)) if !(crossfade >= 0.0 && crossfade.is_finite()) => Err(format!("Segment \"{}\" crossfade must be zero or more seconds, not {}", self.text, crossfade)),
+            (None, Some(crossfade)) => Ok(Join::Crossfade(crossfade)),
+            (gap, None) => Ok(Join::Gap(gap.unwrap_or(0.0))),
+        }
+    }
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Script, String> {
+    let file = File::open(&path).map_err(|err| format!("Failed to open {:?}: {}", path.as_ref(), err))?;
+    let script: Script = serde_json::from_reader(file).map_err(|err| format!("Failed to parse {:?}: {}", path.as_ref(), err))?;
+
+    if script.segments.is_empty() {
+        return Err(format!("{:?} has no segments", path.as_ref()));
+    }
+    for segment in script.segments.iter() {
+        segment.join()?;
+    }
+
+    Ok(script)
+}
+
+/// Audio being assembled from segments, in the format of the first one.
+pub struct Mix {
+    spec: Option<hound::WavSpec>,
+    samples: Vec<f32>,
+}
+
+impl Mix {
+    pub fn new() -> Mix {
+        Mix {
+            spec: None,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Adds a segment, converting it to the mix's format first if the voices differ. Returns
+    /// where the segment starts and ends in the mix, in seconds.
+    pub fn append(&mut self, spec: hound::WavSpec, samples: &[f32], join: &Join) -> (f32, f32) {
+        let target = *self.spec.get_or_insert(hound::WavSpec {
+            channels: spec.channels,
+            sample_rate: spec.sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        });
+        let channels = target.channels as usize;
+
+        let mut mixed = Vec::new();
+        convert::mix_block(samples, spec.channels, &mut mixed, target.channels);
+        let segment = if spec.sample_rate == target.sample_rate {
+            mixed
+        } else {
+            resample::resample(&mixed, target.channels, spec.sample_rate, target.sample_rate)
+        };
+
+        let start = match *join {
+            Join::Gap(secs) => {
+                let frames = (secs * target.sample_rate as f32).round() as usize;
+                self.samples.extend(::std::iter::repeat(0.0).take(frames * channels));
+                self.samples.extend_from_slice(&segment);
+                self.samples.len() - segment.len()
+            },
+            Join::Crossfade(secs) => {
+                // Never longer than either side of the fade.
+                let frames = ((secs * target.sample_rate as f32).round() as usize)
+                    .min(self.samples.len() / channels)
+                    .min(segment.len() / channels);
+                let overlap = self.samples.len() - frames * channels;
+
+                // Equal-power, so the fade doesn't dip in loudness halfway through.
+                for frame in 0..frames {
+                    let position = (frame as f32 + 0.5) / frames as f32 * FRAC_PI_2;
+                    let (fade_out, fade_in) = (position.cos(), position.sin());
+                    for channel in 0..channels {
+                        let index = frame * channels + channel;
+                        let previous = &mut self.samples[overlap + index];
+                        *previous = *previous * fade_out + segment[index] * fade_in;
+                    }
+                }
+                self.samples.extend_from_slice(&segment[frames * channels..]);
+                overlap
+            },
+        };
+
+        let seconds = |samples: usize| (samples / channels) as f32 / target.sample_rate as f32;
+        (seconds(start), seconds(self.samples.len()))
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), convert::ConvertError> {
+        let spec = match self.spec {
+            Some(spec) => spec,
+            None => return Err(convert::ConvertError::Unsupported(String::from("an empty composition"))),
+        };
+
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        convert::write_block(&mut writer, &self.samples)?;
+        writer.finalize()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    fn segment(gap: Option<f32>, crossfade: Option<f32>) -> Segment {
+        Segment {
+            text: String::from("Hello"),
+            preset: None,
+            voice: None,
+            language: None,
+            gender: None,
+            rate: None,
+            pitch: None,
+            gain: None,
+            gap: gap,
+            crossfade: crossfade,
+        }
+    }
+
+    fn spec(channels: u16, sample_rate: u32) -> hound::WavSpec {
+        hound::WavSpec {
+            channels: channels,
+            sample_rate: sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        }
+    }
+
+    #[test]
+    fn join_defaults_to_no_gap() {
+        match segment(None, None).join() {
+            Ok(Join::Gap(secs)) => assert_eq!(secs, 0.0),
+            _ => panic!("expected a zero gap"),
+        }
+        match segment(Some(0.25), None).join() {
+            Ok(Join::Gap(secs)) => assert_eq!(secs, 0.25),
+            _ => panic!("expected a gap"),
+        }
+        match segment(None, Some(0.5)).join() {
+            Ok(Join::Crossfade(secs)) => assert_eq!(secs, 0.5),
+            _ => panic!("expected a crossfade"),
+        }
+    }
+
+    #[test]
+    fn join_rejects_bad_timings() {
+        let err = |gap, crossfade| match segment(gap, crossfade).join() {
+            Err(err) => err,
+            Ok(_) => panic!("{:?} / {:?} should be rejected", gap, crossfade),
+        };
+
+        assert_eq!(err(Some(0.1), Some(0.1)), "Segment \"Hello\" can't have both a gap and a crossfade");
+        assert_eq!(err(Some(-0.1), None), "Segment \"Hello\" gap must be zero or more seconds, not -0.1");
+        assert_eq!(err(None, Some(-1.0)), "Segment \"Hello\" crossfade must be zero or more seconds, not -1");
+        err(Some(::std::f32::NAN), None);
+        err(Some(::std::f32::INFINITY), None);
+        err(None, Some(::std::f32::NAN));
+        err(None, Some(::std::f32::INFINITY));
+    }
+
+    #[test]
+    fn gap_inserts_exact_silence() {
+        let mut mix = Mix::new();
+        assert_eq!(mix.append(spec(2, 16_000), &vec![1.0; 100 * 2], &Join::Gap(0.0)), (0.0, 100.0 / 16_000.0));
+
+        // 0.01 s at 16 kHz is 160 frames.
+        let (start, end) = mix.append(spec(2, 16_000), &vec![0.5; 50 * 2], &Join::Gap(0.01));
+        assert_eq!(start, 260.0 / 16_000.0);
+        assert_eq!(end, 310.0 / 16_000.0);
+
+        assert_eq!(mix.samples.len(), 310 * 2);
+        assert!(mix.samples[..200].iter().all(|&sample| sample == 1.0));
+        assert!(mix.samples[200..520].iter().all(|&sample| sample == 0.0));
+        assert!(mix.samples[520..].iter().all(|&sample| sample == 0.5));
+    }
+
+    #[test]
+    fn crossfade_is_clamped_to_the_shorter_side() {
+        // Longer than the new segment.
+        let mut mix = Mix::new();
+        mix.append(spec(1, 16_000), &vec![1.0; 100], &Join::Gap(0.0));
+        let (start, end) = mix.append(spec(1, 16_000), &vec![1.0; 40], &Join::Crossfade(1.0));
+        assert_eq!((start, end), (60.0 / 16_000.0, 100.0 / 16_000.0));
+        assert_eq!(mix.samples.len(), 100);
+
+        // Longer than the mix so far.
+        let mut mix = Mix::new();
+        mix.append(spec(1, 16_000), &vec![1.0; 30], &Join::Gap(0.0));
+        let (start, end) = mix.append(spec(1, 16_000), &vec![1.0; 100], &Join::Crossfade(1.0));
+        assert_eq!((start, end), (0.0, 100.0 / 16_000.0));
+        assert_eq!(mix.samples.len(), 100);
+    }
+
+    #[test]
+    fn crossfade_keeps_equal_power() {
+        let frames = 64;
+        let fade = |previous: f32, next: f32| {
+            let mut mix = Mix::new();
+            mix.append(spec(2, 16_000), &vec![previous; 200 * 2], &Join::Gap(0.0));
+            mix.append(spec(2, 16_000), &vec![next; 200 * 2], &Join::Crossfade(frames as f32 / 16_000.0));
+            assert_eq!(mix.samples.len(), (400 - frames) * 2);
+            mix.samples[(200 - frames) * 2..200 * 2].to_vec()
+        };
+
+        let fade_out = fade(1.0, 0.0);
+        let fade_in = fade(0.0, 1.0);
+        for frame in 0..frames {
+            let (out_left, out_right) = (fade_out[frame * 2], fade_out[frame * 2 + 1]);
+            let (in_left, in_right) = (fade_in[frame * 2], fade_in[frame * 2 + 1]);
+            assert_eq!(out_left, out_right);
+            assert_eq!(in_left, in_right);
+            assert!((out_left * out_left + in_left * in_left - 1.0).abs() < 1e-6, "power {} at frame {}", out_left * out_left + in_left * in_left, frame);
+            if frame > 0 {
+                assert!(out_left < fade_out[(frame - 1) * 2]);
+                assert!(in_left > fade_in[(frame - 1) * 2]);
+            }
+        }
+        // Symmetrical about the middle.
+        assert!((fade_out[0] - fade_in[(frames - 1) * 2]).abs() < 1e-6);
+    }
+
+    #[test]
+    fn later_segments_are_converted_to_the_first_format() {
+        let mut mix = Mix::new();
+        mix.append(spec(1, 16_000), &vec![0.25; 1600], &Join::Gap(0.0));
+
+        // 0.2 s of 48 kHz stereo becomes 0.2 s of 16 kHz mono.
+        let (start, end) = mix.append(spec(2, 48_000), &vec![0.5; 9600 * 2], &Join::Gap(0.05));
+        assert_eq!(start, 0.15);
+        assert_eq!(end, 0.35);
+        assert_eq!(mix.samples.len(), 1600 + 800 + 3200);
+
+        // And a crossfade is measured in the mix's rate.
+        let (start, end) = mix.append(spec(2, 8_000), &vec![0.5; 800 * 2], &Join::Crossfade(0.05));
+        assert_eq!(start, 0.3);
+        assert_eq!(end, 0.4);
+
+        let written = tempfile::NamedTempFile::new().unwrap();
+        if let Err(err) = mix.write(written.path()) {
+            panic!("writing failed: {}", err);
+        }
+        let reader = hound::WavReader::open(written.path()).unwrap();
+        assert_eq!(reader.spec(), spec(1, 16_000));
+        assert_eq!(reader.len(), 6400);
+    }
+
+    #[test]
+    fn empty_mix_is_not_written() {
+        let written = tempfile::NamedTempFile::new().unwrap();
+        assert!(Mix::new().write(written.path()).is_err());
+    }
+}
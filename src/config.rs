@@ -0,0 +1,380 @@
+use dirs;
+use toml;
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CONFIG_ENV: &str = "SPEECH_TEST_CONFIG";
+const PRESET_ENV: &str = "SPEECH_TEST_PRESET";
+
+/// Voice settings as given by one layer; anything left unset falls through to the layer below.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VoiceSettings {
+    #[serde(default)]
+    #[serde(rename = "name")]
+    pub name: Option<String>,
+
+    #[serde(default)]
+    #[serde(rename = "language")]
+    pub language: Option<String>,
+
+    #[serde(default)]
+    #[serde(rename = "gender")]
+    pub gender: Option<String>,
+
+    #[serde(default)]
+    #[serde(rename = "rate")]
+    pub rate: Option<f32>,
+
+    #[serde(default)]
+    #[serde(rename = "pitch")]
+    pub pitch: Option<f32>,
+
+    #[serde(default)]
+    #[serde(rename = "gain")]
+    pub gain: Option<f32>,
+}
+
+/// The TOML config file:
+///
+/// ```toml
+/// preset = "narrator"        # used when no --preset is given
+///
+/// [defaults]                 # applies to every voice
+/// language = "en-US"
+///
+/// [presets.narrator]
+/// name = "en-US-Wavenet-D"
+/// gender = "MALE"
+/// rate = 0.95
+/// pitch = -2.0
+/// gain = 0.0
+/// ```
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    #[serde(rename = "preset")]
+    preset: Option<String>,
+
+    #[serde(default)]
+    #[serde(rename = "defaults")]
+    defaults: VoiceSettings,
+
+    #[serde(default)]
+    #[serde(rename = "presets")]
+    presets: HashMap<String, VoiceSettings>,
+}
+
+/// A resolved value and the layer it came from.
+pub struct Setting<T> {
+    pub value: T,
+    pub source: String,
+}
+
+impl<T: fmt::Display> fmt::Display for Setting<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.value, self.source)
+    }
+}
+
+fn apply<T: Clone>(setting: &mut Setting<T>, value: &Option<T>, source: &str) {
+    if let Some(ref value) = *value {
+        setting.value = value.clone();
+        setting.source = String::from(source);
+    }
+}
+
+/// The effective voice, after every layer has been applied.
+pub struct Voice {
+    pub preset: Option<String>,
+    pub name: Setting<String>,
+    pub language: Setting<String>,
+    pub gender: Setting<String>,
+    pub rate: Setting<f32>,
+    pub pitch: Setting<f32>,
+    pub gain: Setting<f32>,
+}
+
+impl Voice {
+    fn defaults() -> Voice {
+        let default = |value| Setting { value: value, source: String::from("default") };
+        Voice {
+            preset: None,
+            name: default(String::from("en-US-Wavenet-D")),
+            language: default(String::from("en-US")),
+            gender: default(String::from("MALE")),
+            rate: Setting { value: 1.0, source: String::from("default") },
+            pitch: Setting { value: 0.0, source: String::from("default") },
+            gain: Setting { value: 0.0, source: String::from("default") },
+        }
+    }
+
+    fn apply(&mut self, settings: &VoiceSettings, source: &str) {
+        apply(&mut self.name, &settings.name, source);
+        apply(&mut self.language, &settings.language, source);
+        apply(&mut self.gender, &settings.gender, source);
+        apply(&mut self.rate, &settings.rate, source);
+        apply(&mut self.pitch, &settings.pitch, source);
+        apply(&mut self.gain, &settings.gain, source);
+    }
+}
+
+fn env_value<T: ::std::str::FromStr>(name: &str) -> Result<Option<T>, String> {
+    match env::var(name) {
+        Ok(value) => value.parse().map(Some).map_err(|_| format!("Invalid value for {}: {}", name, value)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn env_settings() -> Result<VoiceSettings, String> {
+    Ok(VoiceSettings {
+        name: env_value("SPEECH_TEST_NAME")?,
+        language: env_value("SPEECH_TEST_LANGUAGE")?,
+        gender: env_value("SPEECH_TEST_GENDER")?,
+        rate: env_value("SPEECH_TEST_RATE")?,
+        pitch: env_value("SPEECH_TEST_PITCH")?,
+        gain: env_value("SPEECH_TEST_GAIN")?,
+    })
+}
+
+/// The config file and environment, ready to be resolved against command line settings.
+///
+/// Values are layered as built-in defaults < config file (its `[defaults]`, then the selected
+/// preset) < `SPEECH_TEST_*` environment variables < command line flags.
+pub struct Layers {
+    path: Option<PathBuf>,
+    file: ConfigFile,
+    env: VoiceSettings,
+    env_preset: Option<String>,
+}
+
+fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("speech-test").join("config.toml"))
+}
+
+/// Loads the config file from `path`, or `$SPEECH_TEST_CONFIG`, or `speech-test/config.toml` in
+/// the user's config directory. Only the last may be missing.
+pub fn load(path: Option<&str>) -> Result<Layers, String> {
+    let explicit = path.map(PathBuf::from).or_else(|| env::var_os(CONFIG_ENV).map(PathBuf::from));
+    let path = match explicit {
+        Some(path) => Some(path),
+        None => default_path().and_then(|path| if path.exists() { Some(path) } else { None }),
+    };
+
+    let file = match path {
+        Some(ref path) => {
+            let text = fs::read_to_string(path).map_err(|err| format!("Failed to read config {:?}: {}", path, err))?;
+            toml::from_str(&text).map_err(|err| format!("Failed to parse config {:?}: {}", path, err))?
+        },
+        None => ConfigFile::default(),
+    };
+
+    Ok(Layers {
+        path: path,
+        file: file,
+        env: env_settings()?,
+        env_preset: env_value(PRESET_ENV)?,
+    })
+}
+
+impl Layers {
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_ref().map(|path| path.as_path())
+    }
+
+    /// Resolves the voice for `preset` (falling back to `$SPEECH_TEST_PRESET`, then the config's
+    /// `preset`) with `overrides` from the command line, or a script, on top.
+    pub fn resolve(&self, preset: Option<&str>, overrides: &VoiceSettings, overrides_source: &str) -> Result<Voice, String> {
+        let mut voice = Voice::defaults();
+        voice.apply(&self.file.defaults, "config");
+
+        let preset = preset.map(String::from).or_else(|| self.env_preset.clone()).or_else(|| self.file.preset.clone());
+        if let Some(ref preset) = preset {
+            let settings = match self.file.presets.get(preset) {
+                Some(settings) => settings,
+                None => {
+                    let mut names: Vec<&str> = self.file.presets.keys().map(|name| name.as_str()).collect();
+                    names.sort();
+                    if names.is_empty() {
+                        return Err(format!("No preset named \"{}\"; the config has no presets", preset));
+                    }
+                    return Err(format!("No preset named \"{}\" in the config; available presets: {}", preset, names.join(", ")));
+                },
+            };
+            voice.apply(settings, &format!("preset {}", preset));
+        }
+        voice.preset = preset;
+
+        voice.apply(&self.env, "environment");
+        voice.apply(overrides, overrides_source);
+        Ok(voice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    use std::io::Write;
+
+    const CONFIG: &'static str = r#"
+preset = "narrator"
+
+[defaults]
+language = "en-GB"
+rate = 1.1
+
+[presets.narrator]
+name = "en-GB-Wavenet-D"
+pitch = -2.0
+
+[presets.child]
+name = "en-GB-Wavenet-A"
+gender = "FEMALE"
+pitch = 4.0
+"#;
+
+    // Built directly rather than loaded, so the tester's own SPEECH_TEST_* variables don't leak in.
+    fn layers(config: &str, env: VoiceSettings, env_preset: Option<&str>) -> Layers {
+        Layers {
+            path: None,
+            file: toml::from_str(config).unwrap(),
+            env: env,
+            env_preset: env_preset.map(String::from),
+        }
+    }
+
+    fn resolve(layers: &Layers, preset: Option<&str>, overrides: &VoiceSettings) -> Voice {
+        match layers.resolve(preset, overrides, "command line") {
+            Ok(voice) => voice,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    fn resolve_err(layers: &Layers, preset: Option<&str>) -> String {
+        match layers.resolve(preset, &VoiceSettings::default(), "command line") {
+            Ok(_) => panic!("preset {:?} should be rejected", preset),
+            Err(err) => err,
+        }
+    }
+
+    fn shown<T: fmt::Display>(setting: &Setting<T>) -> String {
+        setting.to_string()
+    }
+
+    #[test]
+    fn defaults_apply_without_a_config() {
+        let voice = resolve(&layers("", VoiceSettings::default(), None), None, &VoiceSettings::default());
+        assert_eq!(voice.preset, None);
+        assert_eq!(shown(&voice.name), "en-US-Wavenet-D (default)");
+        assert_eq!(shown(&voice.language), "en-US (default)");
+        assert_eq!(shown(&voice.gender), "MALE (default)");
+        assert_eq!(shown(&voice.rate), "1 (default)");
+        assert_eq!(shown(&voice.pitch), "0 (default)");
+        assert_eq!(shown(&voice.gain), "0 (default)");
+    }
+
+    #[test]
+    fn each_layer_overrides_the_one_below() {
+        let env = VoiceSettings {
+            pitch: Some(1.0),
+            gain: Some(-3.0),
+            ..VoiceSettings::default()
+        };
+        let overrides = VoiceSettings {
+            gain: Some(2.0),
+            ..VoiceSettings::default()
+        };
+        let voice = resolve(&layers(CONFIG, env, None), None, &overrides);
+
+        assert_eq!(voice.preset, Some(String::from("narrator")));
+        assert_eq!(shown(&voice.gender), "MALE (default)");
+        assert_eq!(shown(&voice.language), "en-GB (config)");
+        assert_eq!(shown(&voice.rate), "1.1 (config)");
+        assert_eq!(shown(&voice.name), "en-GB-Wavenet-D (preset narrator)");
+        assert_eq!(shown(&voice.pitch), "1 (environment)");
+        assert_eq!(shown(&voice.gain), "2 (command line)");
+    }
+
+    #[test]
+    fn preset_choice_falls_back_to_the_environment_then_the_config() {
+        let config_only = layers(CONFIG, VoiceSettings::default(), None);
+        let with_env = layers(CONFIG, VoiceSettings::default(), Some("child"));
+
+        assert_eq!(resolve(&config_only, None, &VoiceSettings::default()).preset, Some(String::from("narrator")));
+        assert_eq!(resolve(&with_env, None, &VoiceSettings::default()).preset, Some(String::from("child")));
+        let voice = resolve(&with_env, Some("narrator"), &VoiceSettings::default());
+        assert_eq!(voice.preset, Some(String::from("narrator")));
+        assert_eq!(shown(&voice.pitch), "-2 (preset narrator)");
+
+        let voice = resolve(&config_only, Some("child"), &VoiceSettings::default());
+        assert_eq!(shown(&voice.gender), "FEMALE (preset child)");
+        assert_eq!(shown(&voice.pitch), "4 (preset child)");
+    }
+
+    #[test]
+    fn unknown_presets_are_named() {
+        assert_eq!(resolve_err(&layers(CONFIG, VoiceSettings::default(), None), Some("pirate")),
+                   "No preset named \"pirate\" in the config; available presets: child, narrator");
+        assert_eq!(resolve_err(&layers(CONFIG, VoiceSettings::default(), Some("pirate")), None),
+                   "No preset named \"pirate\" in the config; available presets: child, narrator");
+        assert_eq!(resolve_err(&layers("", VoiceSettings::default(), None), Some("pirate")),
+                   "No preset named \"pirate\"; the config has no presets");
+        assert_eq!(resolve_err(&layers("preset = \"pirate\"", VoiceSettings::default(), None), None),
+                   "No preset named \"pirate\"; the config has no presets");
+    }
+
+    fn config_file(text: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn loads_the_given_file() {
+        let file = config_file(CONFIG);
+        let path = file.path().to_str().unwrap();
+        let layers = load(Some(path)).unwrap();
+        assert_eq!(layers.path(), Some(file.path()));
+        assert_eq!(layers.file.preset, Some(String::from("narrator")));
+        assert_eq!(layers.file.presets.len(), 2);
+    }
+
+    #[test]
+    fn reports_unreadable_and_invalid_files() {
+        let file = config_file("[presets.narrator]\nrate = \"fast\"\n");
+        let path = file.path().to_str().unwrap();
+        match load(Some(path)) {
+            Err(err) => assert!(err.starts_with(&format!("Failed to parse config {:?}: ", file.path())), "{}", err),
+            Ok(_) => panic!("the config should be rejected"),
+        }
+
+        let missing = file.path().with_extension("missing");
+        match load(Some(missing.to_str().unwrap())) {
+            Err(err) => assert!(err.starts_with(&format!("Failed to read config {:?}: ", missing)), "{}", err),
+            Ok(_) => panic!("a missing config given by name should be an error"),
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        // A misspelt key would otherwise be silently ignored.
+        for text in &["[presets.narrator]\nrat = 0.9\n", "[defaults]\nvoice = \"en-GB-Wavenet-D\"\n", "presets = {}\ndefault_preset = \"narrator\"\n"] {
+            let file = config_file(text);
+            let path = file.path().to_str().unwrap();
+            match load(Some(path)) {
+                Err(err) => {
+                    assert!(err.starts_with(&format!("Failed to parse config {:?}: ", file.path())), "{}", err);
+                    assert!(err.contains("unknown field"), "{}", err);
+                },
+                Ok(_) => panic!("{:?} should be rejected", text),
+            }
+        }
+    }
+}
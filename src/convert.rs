@@ -0,0 +1,240 @@
+use hound;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use resample::Resampler;
+
+// Frames read from the source per step of the chain; every intermediate buffer is bounded by it.
+const BLOCK_FRAMES: usize = 4096;
+
+pub enum ConvertError {
+    Wav(hound::Error),
+    Unsupported(String),
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConvertError::Wav(ref err) => write!(f, "{}", err),
+            ConvertError::Unsupported(ref what) => write!(f, "Unsupported conversion: {}", what),
+        }
+    }
+}
+
+impl From<hound::Error> for ConvertError {
+    fn from(err: hound::Error) -> ConvertError {
+        ConvertError::Wav(err)
+    }
+}
+
+enum Samples {
+    Float(hound::WavIntoSamples<io::BufReader<fs::File>, f32>),
+    Int(hound::WavIntoSamples<io::BufReader<fs::File>, i32>, f32),
+}
+
+/// Reads a WAV file block by block as interleaved `f32` in [-1.0, 1.0], whatever its format.
+pub struct SampleReader {
+    spec: hound::WavSpec,
+    samples: Samples,
+}
+
+impl SampleReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SampleReader, ConvertError> {
+        let reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+
+        let samples = match spec.sample_format {
+            hound::SampleFormat::Float => Samples::Float(reader.into_samples()),
+            hound::SampleFormat::Int => {
+                let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+                Samples::Int(reader.into_samples(), scale)
+            },
+        };
+
+        Ok(SampleReader {
+            spec: spec,
+            samples: samples,
+        })
+    }
+
+    pub fn spec(&self) -> hound::WavSpec {
+        self.spec
+    }
+
+    /// Replaces the contents of `block` with up to `frames` frames, returning how many were read.
+    pub fn read_block(&mut self, block: &mut Vec<f32>, frames: usize) -> Result<usize, ConvertError> {
+        block.clear();
+
+        let count = frames * self.spec.channels as usize;
+        match self.samples {
+            Samples::Float(ref mut samples) => {
+                for sample in samples.take(count) {
+                    block.push(sample?);
+                }
+            },
+            Samples::Int(ref mut samples, scale) => {
+                for sample in samples.take(count) {
+                    block.push(sample? as f32 / scale);
+                }
+            },
+        }
+
+        Ok(block.len() / self.spec.channels as usize)
+    }
+}
+
+/// Maps one interleaved frame onto `output.len()` channels.
+///
+/// Input channels are folded onto output channels round-robin and averaged, so stereo becomes
+/// the mean of left and right in mono, while mono is duplicated into every output channel.
+pub fn mix_frame(input: &[f32], output: &mut [f32]) {
+    let output_channels = output.len();
+    if input.len() == output_channels {
+        output.copy_from_slice(input);
+        return;
+    }
+
+    for (channel, out) in output.iter_mut().enumerate() {
+        if input.len() < output_channels {
+            *out = input[channel % input.len()];
+        } else {
+            let folded = input.iter().skip(channel).step_by(output_channels);
+            let count = folded.clone().count();
+            *out = folded.sum::<f32>() / count as f32;
+        }
+    }
+}
+
+/// Remaps every frame of an interleaved block from `input_channels` to `output_channels`.
+pub fn mix_block(input: &[f32], input_channels: u16, output: &mut Vec<f32>, output_channels: u16) {
+    output.clear();
+
+    let mut frame = vec![0.0; output_channels as usize];
+    for input_frame in input.chunks(input_channels as usize) {
+        mix_frame(input_frame, &mut frame);
+        output.extend_from_slice(&frame);
+    }
+}
+
+fn write_sample<W: io::Write + io::Seek>(writer: &mut hound::WavWriter<W>, spec: &hound::WavSpec, sample: f32) -> Result<(), hound::Error> {
+    let sample = sample.max(-1.0).min(1.0);
+    match spec.sample_format {
+        hound::SampleFormat::Float => writer.write_sample(sample),
+        hound::SampleFormat::Int => {
+            let max = ((1i64 << (spec.bits_per_sample - 1)) - 1) as f32;
+            writer.write_sample((sample * max).round() as i32)
+        },
+    }
+}
+
+pub fn write_block<W: io::Write + io::Seek>(writer: &mut hound::WavWriter<W>, block: &[f32]) -> Result<(), hound::Error> {
+    let spec = writer.spec();
+    for &sample in block {
+        write_sample(writer, &spec, sample)?;
+    }
+    Ok(())
+}
+
+/// Decodes a complete WAV file held in memory to interleaved `f32` in [-1.0, 1.0].
+pub fn decode_wav(wav: &[u8]) -> Result<(hound::WavSpec, Vec<f32>), ConvertError> {
+    let reader = hound::WavReader::new(io::Cursor::new(wav))?;
+    let spec = reader.spec();
+
+    let mut samples = Vec::with_capacity(reader.len() as usize);
+    match spec.sample_format {
+        hound::SampleFormat::Float => {
+            for sample in reader.into_samples::<f32>() {
+                samples.push(sample?);
+            }
+        },
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            for sample in reader.into_samples::<i32>() {
+                samples.push(sample? as f32 / scale);
+            }
+        },
+    }
+
+    Ok((spec, samples))
+}
+
+/// Encodes interleaved `f32` samples as a complete WAV file in memory.
+pub fn encode_wav(spec: hound::WavSpec, samples: &[f32]) -> Result<Vec<u8>, ConvertError> {
+    check_output_spec(&spec)?;
+
+    let mut output = io::Cursor::new(Vec::new());
+    {
+        let mut writer = hound::WavWriter::new(&mut output, spec)?;
+        write_block(&mut writer, samples)?;
+        writer.finalize()?;
+    }
+
+    Ok(output.into_inner())
+}
+
+pub fn check_output_spec(spec: &hound::WavSpec) -> Result<(), ConvertError> {
+    match (spec.sample_format, spec.bits_per_sample) {
+        (hound::SampleFormat::Int, 8) |
+        (hound::SampleFormat::Int, 16) |
+        (hound::SampleFormat::Int, 24) |
+        (hound::SampleFormat::Int, 32) |
+        (hound::SampleFormat::Float, 32) => {},
+        (format, bits) => return Err(ConvertError::Unsupported(format!("{}-bit {:?} output", bits, format))),
+    }
+
+    if spec.channels == 0 || spec.sample_rate == 0 {
+        return Err(ConvertError::Unsupported(String::from("empty output format")));
+    }
+
+    Ok(())
+}
+
+/// Whether both paths name the same existing file.
+pub fn same_file(input: &Path, output: &Path) -> bool {
+    match (fs::canonicalize(input), fs::canonicalize(output)) {
+        (Ok(input), Ok(output)) => input == output,
+        _ => false,
+    }
+}
+
+/// Converts `input` to exactly `target`, writing the result to `output`.
+///
+/// Audio flows through reader → channel mixer → resampler → writer one block at a time, so
+/// memory use stays constant however long the recording is. Returns the source spec.
+pub fn convert<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q, target: hound::WavSpec) -> Result<hound::WavSpec, ConvertError> {
+    check_output_spec(&target)?;
+
+    // The source is still being read while the output is written.
+    if same_file(input.as_ref(), output.as_ref()) {
+        return Err(ConvertError::Unsupported(String::from("converting a file onto itself")));
+    }
+
+    let mut reader = SampleReader::open(&input)?;
+    let source = reader.spec();
+
+    let mut resampler = Resampler::new(target.channels, source.sample_rate, target.sample_rate);
+    let mut writer = hound::WavWriter::create(&output, target)?;
+
+    let mut block = Vec::with_capacity(BLOCK_FRAMES * source.channels as usize);
+    let mut mixed = Vec::with_capacity(BLOCK_FRAMES * target.channels as usize);
+    let mut resampled = Vec::new();
+
+    while reader.read_block(&mut block, BLOCK_FRAMES)? > 0 {
+        mix_block(&block, source.channels, &mut mixed, target.channels);
+
+        resampled.clear();
+        resampler.process(&mixed, &mut resampled);
+        write_block(&mut writer, &resampled)?;
+    }
+
+    resampled.clear();
+    resampler.flush(&mut resampled);
+    write_block(&mut writer, &resampled)?;
+
+    writer.finalize()?;
+
+    Ok(source)
+}
@@ -0,0 +1,505 @@
+use cpal;
+use rodio;
+use serde_json;
+
+fn sample_format_name(format: cpal::SampleFormat) -> &'static str {
+    match format {
+        cpal::SampleFormat::U16 => "u16",
+        cpal::SampleFormat::I16 => "i16",
+        cpal::SampleFormat::F32 => "f32",
+    }
+}
+
+fn parse_sample_format(name: &str) -> Option<cpal::SampleFormat> {
+    match name.to_lowercase().as_str() {
+        "u16" => Some(cpal::SampleFormat::U16),
+        "i16" => Some(cpal::SampleFormat::I16),
+        "f32" => Some(cpal::SampleFormat::F32),
+        _ => None,
+    }
+}
+
+fn has_input(device: &cpal::Device) -> bool {
+    device.supported_input_formats().map(|mut formats| formats.next().is_some()).unwrap_or(false)
+}
+
+// Numbered the same way as `--enumerate`, so either listing can be used to pick an index.
+fn input_devices() -> Vec<(usize, cpal::Device)> {
+    cpal::devices()
+        .enumerate()
+        .map(|(index, device)| (index + 1, device))
+        .filter(|&(_, ref device)| has_input(device))
+        .collect()
+}
+
+fn list_input_devices(devices: &[(usize, cpal::Device)]) -> String {
+    let mut list = String::from("Available input devices:");
+    for &(index, ref device) in devices {
+        list += &format!("\n  {}. \"{}\"", index, device.name());
+    }
+    list
+}
+
+/// Finds an input device by its index in the device enumeration or by a case-insensitive
+/// substring of its name. An exact name match wins over partial ones.
+pub fn find_input_device(selector: &str) -> Result<cpal::Device, String> {
+    let devices = input_devices();
+
+    if let Ok(index) = selector.parse::<usize>() {
+        return match devices.iter().find(|&&(device_index, _)| device_index == index) {
+            Some(&(_, ref device)) => Ok(device.clone()),
+            None => Err(format!("No input device with index {}. {}", index, list_input_devices(&devices))),
+        };
+    }
+
+    if let Some(&(_, ref device)) = devices.iter().find(|&&(_, ref device)| device.name() == selector) {
+        return Ok(device.clone());
+    }
+
+    let needle = selector.to_lowercase();
+    let matches: Vec<_> = devices.iter()
+        .filter(|&&(_, ref device)| device.name().to_lowercase().contains(&needle))
+        .collect();
+
+    match matches.len() {
+        1 => Ok(matches[0].1.clone()),
+        0 => Err(format!("No input device matches \"{}\". {}", selector, list_input_devices(&devices))),
+        _ => {
+            let matching: Vec<_> = matches.into_iter().cloned().collect();
+            Err(format!("\"{}\" matches more than one input device. {}", selector, list_input_devices(&matching)))
+        },
+    }
+}
+
+// Parses a `<channels>,<sample rate>,<i16|u16|f32>` input format.
+fn parse_input_format(selector: &str) -> Result<cpal::Format, String> {
+    let malformed = || format!("Input format must be <channels>,<sample rate>,<i16|u16|f32>, not \"{}\"", selector);
+
+    let parts: Vec<&str> = selector.split(',').map(|part| part.trim()).collect();
+    if parts.len() != 3 {
+        return Err(malformed());
+    }
+    let channels = match parts[0].parse::<cpal::ChannelCount>() {
+        Ok(channels) if channels > 0 => channels,
+        _ => return Err(malformed()),
+    };
+    let sample_rate = match parts[1].parse::<u32>() {
+        Ok(sample_rate) if sample_rate > 0 => sample_rate,
+        _ => return Err(malformed()),
+    };
+    let data_type = parse_sample_format(parts[2]).ok_or_else(malformed)?;
+
+    Ok(cpal::Format {
+        channels: channels,
+        sample_rate: cpal::SampleRate(sample_rate),
+        data_type: data_type,
+    })
+}
+
+fn is_supported(supported: &[cpal::SupportedFormat], format: &cpal::Format) -> bool {
+    supported.iter().any(|supported| {
+        supported.channels == format.channels
+            && supported.data_type == format.data_type
+            && supported.min_sample_rate <= format.sample_rate
+            && format.sample_rate <= supported.max_sample_rate
+    })
+}
+
+/// Picks a stream format from the device's supported input formats, given as
+/// `<channels>,<sample rate>,<i16|u16|f32>`, e.g. `1,16000,i16`.
+pub fn find_input_format(device: &cpal::Device, selector: &str) -> Result<cpal::Format, String> {
+    let format = parse_input_format(selector)?;
+
+    let supported: Vec<cpal::SupportedFormat> = device.supported_input_formats()
+        .map_err(|err| format!("Failed to list input formats of \"{}\": {:?}", device.name(), err))?
+        .collect();
+
+    if is_supported(&supported, &format) {
+        return Ok(format);
+    }
+
+    let mut list = format!("\"{}\" doesn't support input format {}. Supported input formats:", device.name(), selector);
+    for format in supported {
+        list += &format!("\n  {},{}-{},{}", format.channels, format.min_sample_rate.0, format.max_sample_rate.0, sample_format_name(format.data_type));
+    }
+    Err(list)
+}
+
+/// The input device and format selected on the command line, falling back to the defaults.
+pub fn select_input(device: Option<&str>, format: Option<&str>) -> Result<(cpal::Device, cpal::Format), String> {
+    let device = match device {
+        Some(selector) => find_input_device(selector)?,
+        None => cpal::default_input_device().ok_or_else(|| format!("No default input device. {}", list_input_devices(&input_devices())))?,
+    };
+
+    let format = match format {
+        Some(selector) => find_input_format(&device, selector)?,
+        None => device.default_input_format().map_err(|err| format!("Failed to get default input format of \"{}\": {:?}", device.name(), err))?,
+    };
+
+    Ok((device, format))
+}
+
+// Playback goes through rodio, whose devices come from its own cpal version and needn't match
+// the capture device list in order or contents, so they're numbered separately.
+fn output_endpoints() -> Vec<(usize, rodio::Endpoint)> {
+    rodio::endpoints()
+        .enumerate()
+        .map(|(index, endpoint)| (index + 1, endpoint))
+        .collect()
+}
+
+fn list_output_endpoints<'a, I: Iterator<Item = &'a (usize, rodio::Endpoint)>>(endpoints: I) -> String {
+    let mut list = String::from("Available output devices:");
+    for &(index, ref endpoint) in endpoints {
+        list += &format!("\n  {}. \"{}\"", index, endpoint.name());
+    }
+    list
+}
+
+/// Finds a playback endpoint by its index in the playback device listing or by a
+/// case-insensitive substring of its name. An exact name match wins over partial ones.
+pub fn find_output_endpoint(selector: &str) -> Result<rodio::Endpoint, String> {
+    let mut endpoints = output_endpoints();
+
+    let positions: Vec<usize> = match selector.parse::<usize>() {
+        Ok(index) => endpoints.iter().position(|&(endpoint_index, _)| endpoint_index == index).into_iter().collect(),
+        Err(_) => match endpoints.iter().position(|&(_, ref endpoint)| endpoint.name() == selector) {
+            Some(position) => vec![position],
+            None => {
+                let needle = selector.to_lowercase();
+                (0..endpoints.len()).filter(|&position| endpoints[position].1.name().to_lowercase().contains(&needle)).collect()
+            },
+        },
+    };
+
+    match positions.len() {
+        1 => Ok(endpoints.swap_remove(positions[0]).1),
+        0 if selector.parse::<usize>().is_ok() => Err(format!("No output device with index {}. {}", selector, list_output_endpoints(endpoints.iter()))),
+        0 => Err(format!("No output device matches \"{}\". {}", selector, list_output_endpoints(endpoints.iter()))),
+        _ => Err(format!("\"{}\" matches more than one output device. {}", selector, list_output_endpoints(positions.iter().map(|&position| &endpoints[position])))),
+    }
+}
+
+#[derive(Serialize)]
+pub struct FormatInfo {
+    #[serde(rename = "channels")]
+    pub channels: u16,
+
+    #[serde(rename = "minSampleRate")]
+    pub min_sample_rate: u32,
+
+    #[serde(rename = "maxSampleRate")]
+    pub max_sample_rate: u32,
+
+    #[serde(rename = "sampleFormat")]
+    pub sample_format: &'static str,
+}
+
+impl FormatInfo {
+    fn from_format(format: &cpal::Format) -> FormatInfo {
+        FormatInfo {
+            channels: format.channels,
+            min_sample_rate: format.sample_rate.0,
+            max_sample_rate: format.sample_rate.0,
+            sample_format: sample_format_name(format.data_type),
+        }
+    }
+
+    fn from_supported(format: &cpal::SupportedFormat) -> FormatInfo {
+        FormatInfo {
+            channels: format.channels,
+            min_sample_rate: format.min_sample_rate.0,
+            max_sample_rate: format.max_sample_rate.0,
+            sample_format: sample_format_name(format.data_type),
+        }
+    }
+
+    fn describe(&self) -> String {
+        if self.min_sample_rate == self.max_sample_rate {
+            format!("{} ch, {} Hz, {}", self.channels, self.min_sample_rate, self.sample_format)
+        } else {
+            format!("{} ch, {}-{} Hz, {}", self.channels, self.min_sample_rate, self.max_sample_rate, self.sample_format)
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct DeviceInfo {
+    // 1-based, as accepted by --input-device.
+    #[serde(rename = "index")]
+    pub index: usize,
+
+    #[serde(rename = "name")]
+    pub name: String,
+
+    #[serde(rename = "isDefaultInput")]
+    pub is_default_input: bool,
+
+    #[serde(rename = "isDefaultOutput")]
+    pub is_default_output: bool,
+
+    #[serde(rename = "defaultInputFormat")]
+    pub default_input_format: Option<FormatInfo>,
+
+    #[serde(rename = "defaultOutputFormat")]
+    pub default_output_format: Option<FormatInfo>,
+
+    #[serde(rename = "supportedInputFormats")]
+    pub supported_input_formats: Vec<FormatInfo>,
+
+    #[serde(rename = "supportedOutputFormats")]
+    pub supported_output_formats: Vec<FormatInfo>,
+
+    // Formats that couldn't be queried, e.g. because the device was disconnected meanwhile.
+    #[serde(rename = "errors")]
+    pub errors: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct PlaybackDeviceInfo {
+    // 1-based, as accepted by --output-device.
+    #[serde(rename = "index")]
+    pub index: usize,
+
+    #[serde(rename = "name")]
+    pub name: String,
+
+    #[serde(rename = "isDefault")]
+    pub is_default: bool,
+}
+
+#[derive(Serialize)]
+pub struct Listing {
+    #[serde(rename = "devices")]
+    pub devices: Vec<DeviceInfo>,
+
+    #[serde(rename = "playbackDevices")]
+    pub playback_devices: Vec<PlaybackDeviceInfo>,
+}
+
+/// Describes every audio device and the stream formats it supports, and the devices playback
+/// can use.
+pub fn listing() -> Listing {
+    let default_playback = rodio::default_endpoint().map(|endpoint| endpoint.name());
+    let playback_devices = output_endpoints()
+        .into_iter()
+        .map(|(index, endpoint)| {
+            let name = endpoint.name();
+            PlaybackDeviceInfo {
+                index: index,
+                is_default: default_playback.as_ref() == Some(&name),
+                name: name,
+            }
+        })
+        .collect();
+
+    Listing {
+        devices: device_infos(),
+        playback_devices: playback_devices,
+    }
+}
+
+fn device_infos() -> Vec<DeviceInfo> {
+    let default_input = cpal::default_input_device().map(|device| device.name());
+    let default_output = cpal::default_output_device().map(|device| device.name());
+
+    cpal::devices()
+        .enumerate()
+        .map(|(index, device)| {
+            let name = device.name();
+            let mut errors = Vec::new();
+
+            let supported_input_formats = match device.supported_input_formats() {
+                Ok(formats) => formats.map(|format| FormatInfo::from_supported(&format)).collect(),
+                Err(err) => {
+                    errors.push(format!("Failed to list input formats: {:?}", err));
+                    vec![]
+                },
+            };
+            let supported_output_formats = match device.supported_output_formats() {
+                Ok(formats) => formats.map(|format| FormatInfo::from_supported(&format)).collect(),
+                Err(err) => {
+                    errors.push(format!("Failed to list output formats: {:?}", err));
+                    vec![]
+                },
+            };
+
+            // Output-only devices have no default input format and vice versa, which isn't an error.
+            let default_input_format = match device.default_input_format() {
+                Ok(format) => Some(FormatInfo::from_format(&format)),
+                Err(cpal::DefaultFormatError::StreamTypeNotSupported) => None,
+                Err(err) => {
+                    errors.push(format!("Failed to get default input format: {:?}", err));
+                    None
+                },
+            };
+            let default_output_format = match device.default_output_format() {
+                Ok(format) => Some(FormatInfo::from_format(&format)),
+                Err(cpal::DefaultFormatError::StreamTypeNotSupported) => None,
+                Err(err) => {
+                    errors.push(format!("Failed to get default output format: {:?}", err));
+                    None
+                },
+            };
+
+            DeviceInfo {
+                index: index + 1,
+                is_default_input: default_input.as_ref() == Some(&name),
+                is_default_output: default_output.as_ref() == Some(&name),
+                name: name,
+                default_input_format: default_input_format,
+                default_output_format: default_output_format,
+                supported_input_formats: supported_input_formats,
+                supported_output_formats: supported_output_formats,
+                errors: errors,
+            }
+        })
+        .collect()
+}
+
+pub fn render_json(listing: &Listing) -> String {
+    serde_json::to_string_pretty(listing).unwrap()
+}
+
+pub fn render_text(listing: &Listing) -> String {
+    let mut text = String::from("Devices:\n");
+    for device in &listing.devices {
+        let mut defaults = Vec::new();
+        if device.is_default_input {
+            defaults.push("default input");
+        }
+        if device.is_default_output {
+            defaults.push("default output");
+        }
+
+        text += &format!("{}. \"{}\"", device.index, device.name);
+        if !defaults.is_empty() {
+            text += &format!(" ({})", defaults.join(", "));
+        }
+        text += "\n";
+
+        if let Some(ref format) = device.default_input_format {
+            text += &format!("  Default input stream format:\n    {}\n", format.describe());
+        }
+        if !device.supported_input_formats.is_empty() {
+            text += "  All supported input stream formats:\n";
+            for (format_index, format) in device.supported_input_formats.iter().enumerate() {
+                text += &format!("    {}.{}. {}\n", device.index, format_index + 1, format.describe());
+            }
+        }
+
+        if let Some(ref format) = device.default_output_format {
+            text += &format!("  Default output stream format:\n    {}\n", format.describe());
+        }
+        if !device.supported_output_formats.is_empty() {
+            text += "  All supported output stream formats:\n";
+            for (format_index, format) in device.supported_output_formats.iter().enumerate() {
+                text += &format!("    {}.{}. {}\n", device.index, format_index + 1, format.describe());
+            }
+        }
+
+        for error in &device.errors {
+            text += &format!("  Error: {}\n", error);
+        }
+    }
+
+    text += "Playback devices:\n";
+    for device in &listing.playback_devices {
+        text += &format!("{}. \"{}\"", device.index, device.name);
+        if device.is_default {
+            text += " (default)";
+        }
+        text += "\n";
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(channels: cpal::ChannelCount, sample_rate: u32, data_type: cpal::SampleFormat) -> cpal::Format {
+        cpal::Format {
+            channels: channels,
+            sample_rate: cpal::SampleRate(sample_rate),
+            data_type: data_type,
+        }
+    }
+
+    #[test]
+    fn parses_input_formats() {
+        assert_eq!(parse_input_format("1,16000,i16"), Ok(format(1, 16_000, cpal::SampleFormat::I16)));
+        assert_eq!(parse_input_format("2,44100,U16"), Ok(format(2, 44_100, cpal::SampleFormat::U16)));
+        assert_eq!(parse_input_format(" 2 , 48000 , f32 "), Ok(format(2, 48_000, cpal::SampleFormat::F32)));
+    }
+
+    #[test]
+    fn rejects_malformed_input_formats() {
+        for selector in &["", "1", "1,16000", "16000,i16", "1,16000,i16,x", "1,,i16", ",16000,i16", "0,16000,i16", "1,0,i16",
+                          "-1,16000,i16", "1,-16000,i16", "1.5,16000,i16", "1,16k,i16", "1,16000,", "1,16000,i24", "1,16000,s16"] {
+            assert_eq!(parse_input_format(selector),
+                       Err(format!("Input format must be <channels>,<sample rate>,<i16|u16|f32>, not \"{}\"", selector)));
+        }
+    }
+
+    #[test]
+    fn matches_supported_rate_ranges() {
+        let supported = vec![
+            cpal::SupportedFormat {
+                channels: 1,
+                min_sample_rate: cpal::SampleRate(8_000),
+                max_sample_rate: cpal::SampleRate(48_000),
+                data_type: cpal::SampleFormat::I16,
+            },
+            cpal::SupportedFormat {
+                channels: 2,
+                min_sample_rate: cpal::SampleRate(44_100),
+                max_sample_rate: cpal::SampleRate(44_100),
+                data_type: cpal::SampleFormat::F32,
+            },
+        ];
+
+        assert!(is_supported(&supported, &format(1, 8_000, cpal::SampleFormat::I16)));
+        assert!(is_supported(&supported, &format(1, 48_000, cpal::SampleFormat::I16)));
+        assert!(is_supported(&supported, &format(2, 44_100, cpal::SampleFormat::F32)));
+
+        assert!(!is_supported(&supported, &format(1, 7_999, cpal::SampleFormat::I16)));
+        assert!(!is_supported(&supported, &format(1, 48_001, cpal::SampleFormat::I16)));
+        assert!(!is_supported(&supported, &format(2, 16_000, cpal::SampleFormat::I16)));
+        assert!(!is_supported(&supported, &format(1, 16_000, cpal::SampleFormat::F32)));
+        assert!(!is_supported(&supported, &format(2, 48_000, cpal::SampleFormat::F32)));
+        assert!(!is_supported(&[], &format(1, 16_000, cpal::SampleFormat::I16)));
+    }
+
+    #[test]
+    fn listed_formats_can_be_selected() {
+        for &data_type in &[cpal::SampleFormat::U16, cpal::SampleFormat::I16, cpal::SampleFormat::F32] {
+            let supported = cpal::SupportedFormat {
+                channels: 2,
+                min_sample_rate: cpal::SampleRate(16_000),
+                max_sample_rate: cpal::SampleRate(16_000),
+                data_type: data_type,
+            };
+            let info = FormatInfo::from_supported(&supported);
+            assert_eq!(parse_sample_format(info.sample_format), Some(data_type));
+
+            let selector = format!("{},{},{}", info.channels, info.min_sample_rate, info.sample_format);
+            assert!(is_supported(&[supported], &parse_input_format(&selector).unwrap()));
+        }
+    }
+
+    #[test]
+    fn describes_fixed_and_ranged_rates() {
+        let fixed = FormatInfo::from_format(&format(1, 16_000, cpal::SampleFormat::I16));
+        assert_eq!(fixed.describe(), "1 ch, 16000 Hz, i16");
+
+        let ranged = FormatInfo::from_supported(&cpal::SupportedFormat {
+            channels: 2,
+            min_sample_rate: cpal::SampleRate(8_000),
+            max_sample_rate: cpal::SampleRate(96_000),
+            data_type: cpal::SampleFormat::F32,
+        });
+        assert_eq!(ranged.describe(), "2 ch, 8000-96000 Hz, f32");
+    }
+}
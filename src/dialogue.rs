@@ -0,0 +1,290 @@
+use serde_json;
+
+use std::collections::HashMap;
+
+use compose::Segment;
+
+/// A character's voice settings, from an `@NAME key=value ...` cast line.
+#[derive(Clone, Default)]
+struct Preset {
+    preset: Option<String>,
+    voice: Option<String>,
+    language: Option<String>,
+    gender: Option<String>,
+    rate: Option<f32>,
+    pitch: Option<f32>,
+    gain: Option<f32>,
+}
+
+impl Preset {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let number = || value.parse::<f32>().map_err(|_| format!("{} must be a number, not \"{}\"", key, value));
+        match key {
+            "preset" => self.preset = Some(String::from(value)),
+            "voice" => self.voice = Some(String::from(value)),
+            "language" => self.language = Some(String::from(value)),
+            "gender" => self.gender = Some(value.to_uppercase()),
+            "rate" => self.rate = Some(number()?),
+            "pitch" => self.pitch = Some(number()?),
+            "gain" => self.gain = Some(number()?),
+            _ => return Err(format!("unknown setting \"{}\"; expected preset, voice, language, gender, rate, pitch or gain", key)),
+        }
+        Ok(())
+    }
+}
+
+/// A line of dialogue and who speaks it.
+pub struct Line {
+    pub character: String,
+    pub text: String,
+}
+
+pub struct Dialogue {
+    pub lines: Vec<Line>,
+    pub segments: Vec<Segment>,
+}
+
+// Character names are written in capitals, like NARRATOR or OLD MAN.
+fn looks_like_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_uppercase() || c.is_numeric() || " _-.'".contains(c))
+}
+
+/// Parses a dialogue script, with lines spaced `gap` seconds apart.
+///
+/// ```text
+/// # Cast: each character's voice, set before their first line.
+/// @NARRATOR preset=narrator rate=0.95
+/// @ALICE voice=en-US-Wavenet-C gender=FEMALE pitch=2
+///
+/// NARRATOR: It was a dark and stormy night.
+/// ALICE: Who's there?
+///   Lines that don't start with a name continue the one before.
+/// ```
+pub fn parse(source: &str, gap: f32) -> Result<Dialogue, String> {
+    let mut cast: HashMap<String, Preset> = HashMap::new();
+    let mut lines: Vec<Line> = Vec::new();
+
+    for (index, text) in source.lines().enumerate() {
+        let text = text.trim();
+        let error = |message: String| format!("Line {}: {}", index + 1, message);
+
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+
+        if text.starts_with('@') {
+            let (settings, name): (Vec<&str>, Vec<&str>) = text[1..].split_whitespace().partition(|token| token.contains('='));
+            let name = name.join(" ");
+            if !looks_like_name(&name) {
+                return Err(error(format!("\"{}\" isn't a character name; names are written in capitals", name)));
+            }
+
+            let preset = cast.entry(name).or_insert_with(Preset::default);
+            for setting in settings {
+                let mut parts = setting.splitn(2, '=');
+                let key = parts.next().unwrap();
+                let value = parts.next().unwrap();
+                if value.is_empty() {
+                    return Err(error(format!("{} needs a value, like \"{}=...\"", key, key)));
+                }
+                preset.set(key, value).map_err(&error)?;
+            }
+            continue;
+        }
+
+        if let Some(colon) = text.find(':') {
+            let name = text[..colon].trim();
+            if looks_like_name(name) {
+                if !cast.contains_key(name) {
+                    return Err(error(format!("{} isn't in the cast; add an \"@{} voice=...\" line before it", name, name)));
+                }
+                lines.push(Line {
+                    character: String::from(name),
+                    text: String::from(text[colon + 1..].trim()),
+                });
+                continue;
+            }
+        }
+
+        match lines.last_mut() {
+            Some(line) => {
+                line.text.push(' ');
+                line.text.push_str(text);
+            },
+            None => return Err(error(String::from("expected \"NAME: line\" or an \"@NAME\" cast line"))),
+        }
+    }
+
+    if lines.is_empty() {
+        return Err(String::from("The script has no lines of dialogue"));
+    }
+
+    let segments = lines.iter()
+        .enumerate()
+        .map(|(index, line)| {
+            let preset = cast[&line.character].clone();
+            Segment {
+                text: line.text.clone(),
+                preset: preset.preset,
+                voice: preset.voice,
+                language: preset.language,
+                gender: preset.gender,
+                rate: preset.rate,
+                pitch: preset.pitch,
+                gain: preset.gain,
+                gap: if index == 0 { None } else { Some(gap) },
+                crossfade: None,
+            }
+        })
+        .collect();
+
+    Ok(Dialogue {
+        lines: lines,
+        segments: segments,
+    })
+}
+
+#[derive(Serialize)]
+struct Cue<'a> {
+    #[serde(rename = "line")]
+    line: usize,
+
+    #[serde(rename = "character")]
+    character: &'a str,
+
+    #[serde(rename = "startSecs")]
+    start_secs: f32,
+
+    #[serde(rename = "endSecs")]
+    end_secs: f32,
+
+    #[serde(rename = "text")]
+    text: &'a str,
+}
+
+/// A JSON cue sheet giving when each line starts and ends in the rendered audio.
+pub fn render_cues(lines: &[Line], times: &[(f32, f32)]) -> String {
+    let cues: Vec<Cue> = lines.iter()
+        .zip(times.iter())
+        .enumerate()
+        .map(|(index, (line, &(start_secs, end_secs)))| Cue {
+            line: index + 1,
+            character: &line.character,
+            start_secs: start_secs,
+            end_secs: end_secs,
+            text: &line.text,
+        })
+        .collect();
+
+    serde_json::to_string_pretty(&cues).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &'static str = "\
+# The cast.
+@NARRATOR preset=narrator rate=0.95
+@OLD MAN voice=en-GB-Wavenet-D gender=male pitch=-2.5 gain=-1
+
+NARRATOR: It was a dark and stormy night.
+OLD MAN: Who's there?
+  Speak up,
+  I can't hear you.
+NARRATOR: Nobody answered.
+";
+
+    fn parse_err(source: &str) -> String {
+        match parse(source, 0.5) {
+            Err(err) => err,
+            Ok(_) => panic!("{:?} should be rejected", source),
+        }
+    }
+
+    #[test]
+    fn parses_cast_and_lines() {
+        let dialogue = parse(SCRIPT, 0.5).unwrap();
+
+        let lines: Vec<(&str, &str)> = dialogue.lines.iter().map(|line| (&line.character[..], &line.text[..])).collect();
+        assert_eq!(lines, vec![
+            ("NARRATOR", "It was a dark and stormy night."),
+            ("OLD MAN", "Who's there? Speak up, I can't hear you."),
+            ("NARRATOR", "Nobody answered."),
+        ]);
+
+        let segments = &dialogue.segments;
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0].text, "It was a dark and stormy night.");
+        assert_eq!(segments[0].preset, Some(String::from("narrator")));
+        assert_eq!(segments[0].rate, Some(0.95));
+        assert_eq!(segments[0].voice, None);
+
+        assert_eq!(segments[1].voice, Some(String::from("en-GB-Wavenet-D")));
+        assert_eq!(segments[1].gender, Some(String::from("MALE")));
+        assert_eq!(segments[1].pitch, Some(-2.5));
+        assert_eq!(segments[1].gain, Some(-1.0));
+        assert_eq!(segments[1].preset, None);
+
+        // Lines are spaced by the gap, starting with the first.
+        assert_eq!(segments[0].gap, None);
+        assert_eq!(segments[1].gap, Some(0.5));
+        assert_eq!(segments[2].gap, Some(0.5));
+        assert!(segments.iter().all(|segment| segment.crossfade.is_none()));
+    }
+
+    #[test]
+    fn later_cast_lines_add_to_a_character() {
+        let dialogue = parse("@ALICE voice=a rate=2\n@ALICE rate=3 pitch=1\nALICE: Hi.", 0.0).unwrap();
+        let segment = &dialogue.segments[0];
+        assert_eq!(segment.voice, Some(String::from("a")));
+        assert_eq!(segment.rate, Some(3.0));
+        assert_eq!(segment.pitch, Some(1.0));
+    }
+
+    #[test]
+    fn colons_in_ordinary_text_continue_the_line() {
+        let dialogue = parse("@ALICE\nALICE: Remember:\nthe time is 10:30.", 0.0).unwrap();
+        assert_eq!(dialogue.lines[0].text, "Remember: the time is 10:30.");
+    }
+
+    #[test]
+    fn rejects_characters_not_in_the_cast() {
+        assert_eq!(parse_err("@ALICE\nALICE: Hi.\nBOB: Hello."), "Line 3: BOB isn't in the cast; add an \"@BOB voice=...\" line before it");
+    }
+
+    #[test]
+    fn rejects_bad_cast_lines() {
+        assert_eq!(parse_err("@alice voice=a"), "Line 1: \"alice\" isn't a character name; names are written in capitals");
+        assert_eq!(parse_err("@ voice=a"), "Line 1: \"\" isn't a character name; names are written in capitals");
+        assert_eq!(parse_err("\n@ALICE rate=fast"), "Line 2: rate must be a number, not \"fast\"");
+        assert_eq!(parse_err("@ALICE accent=posh"), "Line 1: unknown setting \"accent\"; expected preset, voice, language, gender, rate, pitch or gain");
+        assert_eq!(parse_err("@ALICE =a"), "Line 1: unknown setting \"\"; expected preset, voice, language, gender, rate, pitch or gain");
+        assert_eq!(parse_err("@ALICE rate="), "Line 1: rate needs a value, like \"rate=...\"");
+        assert_eq!(parse_err("@ALICE voice= pitch=1"), "Line 1: voice needs a value, like \"voice=...\"");
+    }
+
+    #[test]
+    fn rejects_a_continuation_before_the_first_line() {
+        assert_eq!(parse_err("# Cast\n@ALICE\nHello there."), "Line 3: expected \"NAME: line\" or an \"@NAME\" cast line");
+    }
+
+    #[test]
+    fn rejects_a_script_without_lines() {
+        assert_eq!(parse_err("@ALICE voice=a\n# Nothing yet."), "The script has no lines of dialogue");
+        assert_eq!(parse_err(""), "The script has no lines of dialogue");
+    }
+
+    #[test]
+    fn renders_a_cue_per_line() {
+        let dialogue = parse(SCRIPT, 0.5).unwrap();
+        let cues: serde_json::Value = serde_json::from_str(&render_cues(&dialogue.lines, &[(0.0, 1.5), (2.0, 4.25), (4.75, 6.0)])).unwrap();
+
+        let expected: serde_json::Value = serde_json::from_str(r#"[
+            {"line": 1, "character": "NARRATOR", "startSecs": 0.0, "endSecs": 1.5, "text": "It was a dark and stormy night."},
+            {"line": 2, "character": "OLD MAN", "startSecs": 2.0, "endSecs": 4.25, "text": "Who's there? Speak up, I can't hear you."},
+            {"line": 3, "character": "NARRATOR", "startSecs": 4.75, "endSecs": 6.0, "text": "Nobody answered."}
+        ]"#).unwrap();
+        assert_eq!(cues, expected);
+    }
+}
@@ -0,0 +1,559 @@
+use hound;
+
+use std::path::Path;
+
+// https://xiph.org/flac/format.html
+
+// Samples per channel in every frame but the last.
+const BLOCK_SIZE: usize = 4096;
+
+// Highest Rice parameter expressible without the escape code.
+const MAX_RICE_PARAMETER: u32 = 14;
+
+const MAX_PARTITION_ORDER: u32 = 8;
+
+const MAX_FIXED_ORDER: usize = 4;
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    accumulator: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            accumulator: 0,
+            bits: 0,
+        }
+    }
+
+    fn write(&mut self, value: u64, bits: u32) {
+        debug_assert!(bits <= 32);
+        if bits == 0 {
+            return;
+        }
+
+        self.accumulator = (self.accumulator << bits) | (value & ((1u64 << bits) - 1));
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.accumulator >> self.bits) as u8);
+        }
+        self.accumulator &= (1u64 << self.bits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    fn write_unary(&mut self, mut zeros: u64) {
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    // Frame numbers use the UTF-8 style variable length coding, extended up to 36 bits.
+    fn write_utf8(&mut self, value: u64) {
+        if value < 0x80 {
+            self.write(value, 8);
+            return;
+        }
+
+        let mut count = 2;
+        while value >= 1u64 << (5 * count + 1) {
+            count += 1;
+        }
+
+        let lead = (0xFFu64 << (8 - count)) & 0xFF;
+        self.write(lead | (value >> (6 * (count - 1))), 8);
+        for index in (0..count - 1).rev() {
+            self.write(0x80 | ((value >> (6 * index)) & 0x3F), 8);
+        }
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            let padding = 8 - self.bits;
+            self.write(0, padding);
+        }
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn fixed_residual(samples: &[i32], order: usize, residual: &mut Vec<i64>) {
+    residual.clear();
+    for i in order..samples.len() {
+        let x = |offset: usize| samples[i - offset] as i64;
+        residual.push(match order {
+            0 => x(0),
+            1 => x(0) - x(1),
+            2 => x(0) - 2 * x(1) + x(2),
+            3 => x(0) - 3 * x(1) + 3 * x(2) - x(3),
+            _ => x(0) - 4 * x(1) + 6 * x(2) - 4 * x(3) + x(4),
+        });
+    }
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+// Estimated size of a Rice partition from the sum of its zigzagged residuals.
+fn rice_parameter(sum: u64, count: usize) -> (u32, u64) {
+    let mut best = (0, u64::max_value());
+    for parameter in 0..MAX_RICE_PARAMETER + 1 {
+        let bits = count as u64 * (parameter as u64 + 1) + (sum >> parameter);
+        if bits < best.1 {
+            best = (parameter, bits);
+        }
+    }
+    best
+}
+
+struct Residual {
+    partition_order: u32,
+    parameters: Vec<u32>,
+    bits: u64,
+}
+
+// Picks the partition order and per-partition Rice parameters for a residual signal.
+fn plan_residual(residual: &[i64], block_size: usize, predictor_order: usize) -> Residual {
+    let mut max_order = 0;
+    while max_order < MAX_PARTITION_ORDER
+        && block_size % (1 << (max_order + 1)) == 0
+        && (block_size >> (max_order + 1)) > predictor_order {
+        max_order += 1;
+    }
+
+    // Sums of the finest partitions, merged pairwise for each coarser order.
+    let partitions = 1usize << max_order;
+    let partition_size = block_size >> max_order;
+    let mut sums = vec![0u64; partitions];
+    let mut start = 0;
+    for (partition, sum) in sums.iter_mut().enumerate() {
+        let end = (partition + 1) * partition_size - predictor_order;
+        *sum = residual[start..end].iter().map(|&value| zigzag(value)).sum();
+        start = end;
+    }
+
+    let mut best: Option<Residual> = None;
+    let mut order = max_order;
+    loop {
+        let partition_size = block_size >> order;
+        let mut parameters = Vec::with_capacity(sums.len());
+        let mut bits = 2 + 4;
+        for (partition, &sum) in sums.iter().enumerate() {
+            let count = if partition == 0 { partition_size - predictor_order } else { partition_size };
+            let (parameter, partition_bits) = rice_parameter(sum, count);
+            parameters.push(parameter);
+            bits += 4 + partition_bits;
+        }
+
+        let better = match best {
+            Some(ref best) => bits < best.bits,
+            None => true,
+        };
+        if better {
+            best = Some(Residual {
+                partition_order: order,
+                parameters: parameters,
+                bits: bits,
+            });
+        }
+
+        if order == 0 {
+            break;
+        }
+        sums = sums.chunks(2).map(|pair| pair.iter().sum()).collect();
+        order -= 1;
+    }
+
+    best.unwrap()
+}
+
+fn write_residual(writer: &mut BitWriter, residual: &[i64], plan: &Residual, block_size: usize, predictor_order: usize) {
+    // Coding method 0: 4-bit Rice parameters.
+    writer.write(0, 2);
+    writer.write(plan.partition_order as u64, 4);
+
+    let partition_size = block_size >> plan.partition_order;
+    let mut start = 0;
+    for (partition, &parameter) in plan.parameters.iter().enumerate() {
+        let end = (partition + 1) * partition_size - predictor_order;
+        writer.write(parameter as u64, 4);
+        for &value in &residual[start..end] {
+            let value = zigzag(value);
+            writer.write_unary(value >> parameter);
+            writer.write(value, parameter);
+        }
+        start = end;
+    }
+}
+
+fn write_subframe(writer: &mut BitWriter, samples: &[i32], bits_per_sample: u32) {
+    let block_size = samples.len();
+
+    if samples.iter().all(|&sample| sample == samples[0]) {
+        writer.write(0, 8);
+        writer.write_signed(samples[0] as i64, bits_per_sample);
+        return;
+    }
+
+    let mut residual = Vec::with_capacity(block_size);
+    let mut best: Option<(usize, Residual)> = None;
+    for order in 0..MAX_FIXED_ORDER.min(block_size - 1) + 1 {
+        fixed_residual(samples, order, &mut residual);
+        let plan = plan_residual(&residual, block_size, order);
+        let bits = plan.bits + (order as u64) * bits_per_sample as u64;
+        let better = match best {
+            Some((best_order, ref best_plan)) => bits < best_plan.bits + (best_order as u64) * bits_per_sample as u64,
+            None => true,
+        };
+        if better {
+            best = Some((order, plan));
+        }
+    }
+
+    let (order, plan) = best.unwrap();
+    let fixed_bits = plan.bits + (order as u64) * bits_per_sample as u64;
+    if fixed_bits >= (block_size as u64) * bits_per_sample as u64 {
+        writer.write(0b000010, 8);
+        for &sample in samples {
+            writer.write_signed(sample as i64, bits_per_sample);
+        }
+        return;
+    }
+
+    writer.write((0b001000 | order as u64) << 1, 8);
+    for &sample in &samples[..order] {
+        writer.write_signed(sample as i64, bits_per_sample);
+    }
+    fixed_residual(samples, order, &mut residual);
+    write_residual(writer, &residual, &plan, block_size, order);
+}
+
+// Frame headers repeat the sample size, since some decoders won't take it from STREAMINFO.
+fn sample_size_code(bits_per_sample: u16) -> Option<u64> {
+    match bits_per_sample {
+        8 => Some(0b001),
+        12 => Some(0b010),
+        16 => Some(0b100),
+        20 => Some(0b101),
+        24 => Some(0b110),
+        _ => None,
+    }
+}
+
+/// Lossless FLAC encoder using fixed linear predictors and partitioned Rice coding.
+///
+/// Samples are buffered until a full block is available, and the whole stream is kept in
+/// memory since it ends up base64-encoded into a request body anyway.
+pub struct Encoder {
+    channels: usize,
+    bits_per_sample: u32,
+    pending: Vec<i32>,
+    channel_samples: Vec<i32>,
+    frame_number: u64,
+    total_samples: u64,
+    output: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new(channels: u16, sample_rate: u32, bits_per_sample: u16) -> Result<Encoder, String> {
+        if channels < 1 || channels > 8 {
+            return Err(format!("FLAC supports 1 to 8 channels, not {}", channels));
+        }
+        if sample_size_code(bits_per_sample).is_none() {
+            return Err(format!("FLAC encoding of {}-bit samples is not supported", bits_per_sample));
+        }
+        if sample_rate == 0 || sample_rate >= 1 << 20 {
+            return Err(format!("FLAC does not support a sample rate of {} Hz", sample_rate));
+        }
+
+        let mut header = BitWriter::new();
+        header.write(0x664C_6143, 32); // "fLaC"
+
+        // Last metadata block, STREAMINFO, 34 bytes.
+        header.write(1, 1);
+        header.write(0, 7);
+        header.write(34, 24);
+
+        header.write(BLOCK_SIZE as u64, 16);
+        header.write(BLOCK_SIZE as u64, 16);
+        header.write(0, 24); // Minimum frame size unknown
+        header.write(0, 24); // Maximum frame size unknown
+        header.write(sample_rate as u64, 20);
+        header.write(channels as u64 - 1, 3);
+        header.write(bits_per_sample as u64 - 1, 5);
+        header.write(0, 4); // Total samples (36 bits), patched in `finish`
+        header.write(0, 32);
+        for _ in 0..4 {
+            header.write(0, 32); // MD5 unknown
+        }
+
+        Ok(Encoder {
+            channels: channels as usize,
+            bits_per_sample: bits_per_sample as u32,
+            pending: Vec::with_capacity(BLOCK_SIZE * channels as usize),
+            channel_samples: Vec::with_capacity(BLOCK_SIZE),
+            frame_number: 0,
+            total_samples: 0,
+            output: header.into_bytes(),
+        })
+    }
+
+    fn write_frame(&mut self) {
+        let block_size = self.pending.len() / self.channels;
+        if block_size == 0 {
+            return;
+        }
+
+        let mut writer = BitWriter::new();
+        writer.write(0b11111111111110, 14); // Sync code
+        writer.write(0, 1); // Reserved
+        writer.write(0, 1); // Fixed block size stream
+        writer.write(0b0111, 4); // 16-bit block size follows the header
+        writer.write(0b0000, 4); // Sample rate from STREAMINFO
+        writer.write(self.channels as u64 - 1, 4); // Independent channels
+        writer.write(sample_size_code(self.bits_per_sample as u16).unwrap(), 3);
+        writer.write(0, 1); // Reserved
+        writer.write_utf8(self.frame_number);
+        writer.write(block_size as u64 - 1, 16);
+        let crc = crc8(&writer.bytes);
+        writer.write(crc as u64, 8);
+
+        for channel in 0..self.channels {
+            self.channel_samples.clear();
+            self.channel_samples.extend(self.pending.iter().skip(channel).step_by(self.channels));
+            write_subframe(&mut writer, &self.channel_samples, self.bits_per_sample);
+        }
+
+        let mut frame = writer.into_bytes();
+        let crc = crc16(&frame);
+        frame.push((crc >> 8) as u8);
+        frame.push(crc as u8);
+        self.output.extend_from_slice(&frame);
+
+        self.frame_number += 1;
+        self.total_samples += block_size as u64;
+        self.pending.clear();
+    }
+
+    /// Adds interleaved samples, encoding every block that fills up.
+    pub fn write_samples(&mut self, samples: &[i32]) {
+        for &sample in samples {
+            self.pending.push(sample);
+            if self.pending.len() == BLOCK_SIZE * self.channels {
+                self.write_frame();
+            }
+        }
+    }
+
+    /// Encodes the final partial block and returns the complete FLAC stream.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.write_frame();
+
+        // Total samples are the 36 bits starting in the low nibble of byte 21 of the stream,
+        // whose high nibble holds the end of the bits per sample field.
+        let total = self.total_samples;
+        self.output[21] = (self.output[21] & 0xF0) | ((total >> 32) as u8 & 0x0F);
+        self.output[22] = (total >> 24) as u8;
+        self.output[23] = (total >> 16) as u8;
+        self.output[24] = (total >> 8) as u8;
+        self.output[25] = total as u8;
+
+        self.output
+    }
+}
+
+/// Encodes an integer PCM WAV file as FLAC.
+pub fn encode_wav<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, String> {
+    let reader = hound::WavReader::open(path).map_err(|err| format!("{}", err))?;
+    let spec = reader.spec();
+    if spec.sample_format != hound::SampleFormat::Int {
+        return Err(String::from("FLAC encoding requires integer PCM samples"));
+    }
+
+    let mut encoder = Encoder::new(spec.channels, spec.sample_rate, spec.bits_per_sample)?;
+    let mut block = Vec::with_capacity(BLOCK_SIZE * spec.channels as usize);
+    for sample in reader.into_samples::<i32>() {
+        block.push(sample.map_err(|err| format!("{}", err))?);
+        if block.len() == block.capacity() {
+            encoder.write_samples(&block);
+            block.clear();
+        }
+    }
+    encoder.write_samples(&block);
+
+    Ok(encoder.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use claxon;
+    use hound;
+    use tempfile;
+
+    use test_signal::{self, Noise};
+
+    use std::io::Cursor;
+
+    // Deterministic full-range noise, so failures reproduce.
+    fn noise(count: usize, bits_per_sample: u16, seed: u32) -> Vec<i32> {
+        let mut noise = Noise::new(seed);
+        (0..count).map(|_| (noise.next_u32() as i32) >> (32 - bits_per_sample)).collect()
+    }
+
+    // A different tone on each channel, so channel mixups show.
+    fn sine(count: usize, channels: usize, amplitude: f64) -> Vec<i32> {
+        let tones: Vec<Vec<f64>> = (0..channels)
+            .map(|channel| test_signal::sine(80.0 * (channel + 1) as f64, amplitude, 0.0, 16_000, count / channels))
+            .collect();
+        test_signal::interleave(&tones).iter().map(|sample| sample.round() as i32).collect()
+    }
+
+    fn encode(samples: &[i32], channels: u16, bits_per_sample: u16) -> Vec<u8> {
+        let mut encoder = Encoder::new(channels, 16_000, bits_per_sample).unwrap();
+        // Uneven chunks, so blocks fill up across calls.
+        for chunk in samples.chunks(1_000) {
+            encoder.write_samples(chunk);
+        }
+        encoder.finish()
+    }
+
+    fn assert_round_trip(samples: &[i32], channels: u16, bits_per_sample: u16) {
+        let flac = encode(samples, channels, bits_per_sample);
+
+        let mut reader = claxon::FlacReader::new(Cursor::new(flac)).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!(info.channels, channels as u32);
+        assert_eq!(info.sample_rate, 16_000);
+        assert_eq!(info.bits_per_sample, bits_per_sample as u32);
+        assert_eq!(info.samples, Some((samples.len() / channels as usize) as u64));
+
+        let decoded: Vec<i32> = reader.samples().map(|sample| sample.unwrap()).collect();
+        assert!(decoded == samples, "{}-bit, {} channel audio didn't survive the round trip", bits_per_sample, channels);
+    }
+
+    // The subframe header byte of one channel's block.
+    fn subframe_type(samples: &[i32], bits_per_sample: u32) -> u8 {
+        let mut writer = BitWriter::new();
+        write_subframe(&mut writer, samples, bits_per_sample);
+        writer.into_bytes()[0]
+    }
+
+    #[test]
+    fn mono_and_stereo() {
+        assert_round_trip(&sine(BLOCK_SIZE * 3, 1, 12_000.0), 1, 16);
+        assert_round_trip(&sine(BLOCK_SIZE * 3 * 2, 2, 12_000.0), 2, 16);
+    }
+
+    #[test]
+    fn partial_final_block() {
+        assert_round_trip(&sine(BLOCK_SIZE * 2 + 100, 1, 9_000.0), 1, 16);
+        assert_round_trip(&sine((BLOCK_SIZE + 1) * 2, 2, 9_000.0), 2, 16);
+        assert_round_trip(&sine(5, 1, 9_000.0), 1, 16);
+    }
+
+    #[test]
+    fn silence_uses_constant_subframes() {
+        assert_eq!(subframe_type(&vec![0; BLOCK_SIZE], 16), 0b0000_0000);
+        assert_round_trip(&vec![0; BLOCK_SIZE * 2 + 10], 1, 16);
+        assert_round_trip(&vec![-3; BLOCK_SIZE * 2], 2, 16);
+    }
+
+    #[test]
+    fn full_scale_noise_uses_verbatim_subframes() {
+        assert_eq!(subframe_type(&noise(BLOCK_SIZE, 16, 1), 16), 0b0000_0010);
+        assert_round_trip(&noise(BLOCK_SIZE * 2 + 333, 1, 7), 1, 16);
+        assert_round_trip(&noise((BLOCK_SIZE + 33) * 2, 2, 9), 2, 16);
+    }
+
+    #[test]
+    fn smooth_audio_uses_fixed_predictors() {
+        let header = subframe_type(&sine(BLOCK_SIZE, 1, 12_000.0), 16);
+        assert_eq!(header & 0b0111_0000, 0b0001_0000);
+        assert!((header >> 1) & 0b111 > 0);
+    }
+
+    #[test]
+    fn extremes_of_the_sample_range() {
+        let mut samples = vec![i16::max_value() as i32, i16::min_value() as i32, 0, -1, 1];
+        samples.extend(sine(BLOCK_SIZE, 1, 32_767.0));
+        assert_round_trip(&samples, 1, 16);
+    }
+
+    #[test]
+    fn eight_and_twenty_four_bit() {
+        assert_round_trip(&sine(BLOCK_SIZE + 50, 1, 120.0), 1, 8);
+        assert_round_trip(&noise(BLOCK_SIZE * 2, 8, 3), 2, 8);
+        assert_round_trip(&vec![-128; 600], 1, 8);
+
+        assert_round_trip(&sine(BLOCK_SIZE + 50, 1, 8_000_000.0), 1, 24);
+        assert_round_trip(&noise(BLOCK_SIZE * 2, 24, 5), 2, 24);
+        assert_round_trip(&vec![8_388_607; 600], 1, 24);
+    }
+
+    #[test]
+    fn rejects_unsupported_formats() {
+        assert!(Encoder::new(0, 16_000, 16).is_err());
+        assert!(Encoder::new(9, 16_000, 16).is_err());
+        assert!(Encoder::new(1, 16_000, 32).is_err());
+        assert!(Encoder::new(1, 0, 16).is_err());
+    }
+
+    #[test]
+    fn encodes_wav_files() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 16_000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let samples = sine(BLOCK_SIZE * 2 + 6, 2, 20_000.0);
+
+        let mut writer = hound::WavWriter::create(file.path(), spec).unwrap();
+        for &sample in &samples {
+            writer.write_sample(sample as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let flac = encode_wav(file.path()).unwrap();
+        let mut reader = claxon::FlacReader::new(Cursor::new(flac)).unwrap();
+        let decoded: Vec<i32> = reader.samples().map(|sample| sample.unwrap()).collect();
+        assert!(decoded == samples);
+    }
+}
@@ -0,0 +1,363 @@
+use std::f64::consts::PI;
+
+use convert::{self, ConvertError};
+use resample;
+
+// ITU-R BS.1770-4 gating: 400 ms blocks overlapping by 75%, an absolute gate at -70 LUFS and a
+// relative gate 10 LU below the loudness of the blocks that pass it.
+const BLOCK_SECS: f64 = 0.4;
+const BLOCK_STEP_SECS: f64 = 0.1;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+// True peak is measured at four times the sample rate, per BS.1770-4 Annex 2.
+const OVERSAMPLING: u32 = 4;
+
+// How fast the limiter pulls the gain down ahead of a peak, and lets it recover after.
+const LIMITER_ATTACK_SECS: f64 = 0.005;
+const LIMITER_RELEASE_SECS: f64 = 0.1;
+
+fn db_to_gain(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+fn gain_to_db(gain: f64) -> f64 {
+    20.0 * gain.log10()
+}
+
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b0 * input + self.z1;
+        self.z1 = self.b1 * input - self.a1 * output + self.z2;
+        self.z2 = self.b2 * input - self.a2 * output;
+        output
+    }
+}
+
+// The K-weighting pre-filter's high shelf and high-pass stages, derived for any sample rate so
+// they match the standard's 48 kHz coefficients there.
+fn k_weighting(sample_rate: f64) -> (Biquad, Biquad) {
+    let k = (PI * 1681.974450955533 / sample_rate).tan();
+    let q = 0.7071752369554196;
+    let vh = db_to_gain(3.999843853973347);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b0: (vh + vb * k / q + k * k) / a0,
+        b1: 2.0 * (k * k - vh) / a0,
+        b2: (vh - vb * k / q + k * k) / a0,
+        a1: 2.0 * (k * k - 1.0) / a0,
+        a2: (1.0 - k / q + k * k) / a0,
+        z1: 0.0,
+        z2: 0.0,
+    };
+
+    let k = (PI * 38.13547087602444 / sample_rate).tan();
+    let q = 0.5003270373238773;
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b0: 1.0,
+        b1: -2.0,
+        b2: 1.0,
+        a1: 2.0 * (k * k - 1.0) / a0,
+        a2: (1.0 - k / q + k * k) / a0,
+        z1: 0.0,
+        z2: 0.0,
+    };
+
+    (shelf, high_pass)
+}
+
+fn block_loudness(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// Gated integrated loudness in LUFS, or negative infinity if nothing passes the gates.
+///
+/// Every channel is weighted equally, which is right for the mono and stereo audio this tool
+/// deals with; surround weighting isn't applied.
+pub fn integrated_loudness(samples: &[f32], channels: usize, sample_rate: u32) -> f64 {
+    let frames = samples.len() / channels;
+
+    // Mean square of the K-weighted signal, summed over channels, for each step.
+    let step = ((sample_rate as f64 * BLOCK_STEP_SECS) as usize).max(1);
+    let mut steps = vec![0.0f64; (frames + step - 1) / step];
+    for channel in 0..channels {
+        let (mut shelf, mut high_pass) = k_weighting(sample_rate as f64);
+        for frame in 0..frames {
+            let weighted = high_pass.process(shelf.process(samples[frame * channels + channel] as f64));
+            steps[frame / step] += weighted * weighted;
+        }
+    }
+
+    // Prompts shorter than a block are measured as one block.
+    let steps_per_block = (BLOCK_SECS / BLOCK_STEP_SECS).round() as usize;
+    let blocks: Vec<f64> = if steps.len() < steps_per_block {
+        vec![steps.iter().sum::<f64>() / frames.max(1) as f64]
+    } else {
+        steps.windows(steps_per_block)
+            .map(|block| block.iter().sum::<f64>() / (step * steps_per_block) as f64)
+            .collect()
+    };
+
+    let gated_mean = |threshold: f64| {
+        let gated: Vec<f64> = blocks.iter().cloned().filter(|&power| block_loudness(power) > threshold).collect();
+        if gated.is_empty() {
+            None
+        } else {
+            Some(gated.iter().sum::<f64>() / gated.len() as f64)
+        }
+    };
+
+    let absolute = match gated_mean(ABSOLUTE_GATE_LUFS) {
+        Some(power) => power,
+        None => return ::std::f64::NEG_INFINITY,
+    };
+    match gated_mean(block_loudness(absolute) + RELATIVE_GATE_LU) {
+        Some(power) => block_loudness(power),
+        None => ::std::f64::NEG_INFINITY,
+    }
+}
+
+/// The largest magnitude around each frame once oversampled, so peaks that fall between samples
+/// and would clip after conversion to analog are caught.
+fn frame_true_peaks(samples: &[f32], channels: usize, sample_rate: u32) -> Vec<f64> {
+    let oversampled = resample::resample(samples, channels as u16, sample_rate, sample_rate * OVERSAMPLING);
+
+    let frames = samples.len() / channels;
+    let mut peaks: Vec<f64> = samples.chunks(channels)
+        .map(|frame| frame.iter().fold(0.0f64, |peak, sample| peak.max((*sample as f64).abs())))
+        .collect();
+
+    let oversampling = OVERSAMPLING as usize;
+    for (index, frame) in oversampled.chunks(channels).enumerate() {
+        // Credit each oversampled point to the input frames either side of it.
+        let before = (index / oversampling).min(frames.saturating_sub(1));
+        let after = ((index + oversampling - 1) / oversampling).min(frames.saturating_sub(1));
+        let peak = frame.iter().fold(0.0f64, |peak, sample| peak.max((*sample as f64).abs()));
+        for neighbour in before..after + 1 {
+            if neighbour < peaks.len() {
+                peaks[neighbour] = peaks[neighbour].max(peak);
+            }
+        }
+    }
+
+    peaks
+}
+
+/// True peak in dBTP.
+pub fn true_peak(samples: &[f32], channels: usize, sample_rate: u32) -> f64 {
+    let peak = frame_true_peaks(samples, channels, sample_rate).iter().fold(0.0f64, |peak, &frame| peak.max(frame));
+    gain_to_db(peak)
+}
+
+/// Scales the samples by `gain`, pulling it down smoothly wherever the true peak would pass
+/// `ceiling`. Returns whether any limiting was needed.
+fn apply_limited_gain(samples: &mut [f32], channels: usize, sample_rate: u32, gain: f64, ceiling: f64) -> bool {
+    let peaks = frame_true_peaks(samples, channels, sample_rate);
+    let mut gains: Vec<f64> = peaks.iter()
+        .map(|&peak| if peak * gain > ceiling { ceiling / peak } else { gain })
+        .collect();
+    let limited = gains.iter().any(|&frame_gain| frame_gain < gain);
+
+    if limited {
+        let release = (-1.0 / (LIMITER_RELEASE_SECS * sample_rate as f64)).exp();
+        let attack = (-1.0 / (LIMITER_ATTACK_SECS * sample_rate as f64)).exp();
+
+        // Recover towards full gain after each peak, then ramp down ahead of it, never rising
+        // above the gain a frame needs.
+        for frame in 1..gains.len() {
+            let recovered = gain - (gain - gains[frame - 1]) * release;
+            gains[frame] = gains[frame].min(recovered);
+        }
+        for frame in (0..gains.len().saturating_sub(1)).rev() {
+            let ramped = gain - (gain - gains[frame + 1]) * attack;
+            gains[frame] = gains[frame].min(ramped);
+        }
+    }
+
+    for (frame, &frame_gain) in samples.chunks_mut(channels).zip(gains.iter()) {
+        for sample in frame.iter_mut() {
+            *sample = (*sample as f64 * frame_gain) as f32;
+        }
+    }
+
+    limited
+}
+
+pub struct Normalized {
+    pub input_lufs: f64,
+    pub output_lufs: f64,
+    pub true_peak_dbtp: f64,
+    pub gain_db: f64,
+    pub limited: bool,
+}
+
+/// Brings interleaved samples to `target_lufs`, limiting so the true peak stays at or below
+/// `ceiling_dbtp`. Silent audio is left alone.
+pub fn normalize(samples: &mut [f32], channels: usize, sample_rate: u32, target_lufs: f64, ceiling_dbtp: f64) -> Normalized {
+    let input_lufs = integrated_loudness(samples, channels, sample_rate);
+
+    let (gain_db, limited) = if input_lufs.is_finite() {
+        let gain_db = target_lufs - input_lufs;
+        let limited = apply_limited_gain(samples, channels, sample_rate, db_to_gain(gain_db), db_to_gain(ceiling_dbtp));
+        (gain_db, limited)
+    } else {
+        (0.0, false)
+    };
+
+    Normalized {
+        input_lufs: input_lufs,
+        output_lufs: integrated_loudness(samples, channels, sample_rate),
+        true_peak_dbtp: true_peak(samples, channels, sample_rate),
+        gain_db: gain_db,
+        limited: limited,
+    }
+}
+
+/// Normalizes a complete WAV file held in memory, returning the new file in the same format.
+pub fn normalize_wav(wav: &[u8], target_lufs: f64, ceiling_dbtp: f64) -> Result<(Vec<u8>, Normalized), ConvertError> {
+    let (spec, mut samples) = convert::decode_wav(wav)?;
+    let normalized = normalize(&mut samples, spec.channels as usize, spec.sample_rate, target_lufs, ceiling_dbtp);
+    Ok((convert::encode_wav(spec, &samples)?, normalized))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_signal;
+
+    // The same tone on every channel.
+    fn sine(hz: f64, amplitude: f64, phase: f64, secs: f64, channels: usize, sample_rate: u32) -> Vec<f32> {
+        let tone = test_signal::sine(hz, amplitude, phase, sample_rate, (secs * sample_rate as f64) as usize);
+        test_signal::interleave(&vec![tone; channels]).iter().map(|&sample| sample as f32).collect()
+    }
+
+    // A 997 Hz tone at -20 dBFS RMS, the level BS.1770 calibrates against.
+    fn reference_tone(secs: f64, channels: usize, sample_rate: u32) -> Vec<f32> {
+        sine(997.0, db_to_gain(-20.0) * 2f64.sqrt(), 0.0, secs, channels, sample_rate)
+    }
+
+    fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+        assert!((actual - expected).abs() <= tolerance, "{} is not within {} of {}", actual, tolerance, expected);
+    }
+
+    #[test]
+    fn reference_tone_reads_minus_20_lufs_per_channel() {
+        for &sample_rate in &[48_000, 44_100, 16_000] {
+            assert_near(integrated_loudness(&reference_tone(3.0, 1, sample_rate), 1, sample_rate), -20.0, 0.1);
+            // Both channels count in full, so stereo is 3 dB louder.
+            assert_near(integrated_loudness(&reference_tone(3.0, 2, sample_rate), 2, sample_rate), -16.99, 0.1);
+        }
+    }
+
+    #[test]
+    fn short_prompts_are_measured_as_one_block() {
+        assert_near(integrated_loudness(&reference_tone(0.3, 1, 48_000), 1, 48_000), -20.0, 0.1);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        assert_eq!(integrated_loudness(&vec![0.0; 48_000], 1, 48_000), ::std::f64::NEG_INFINITY);
+        assert_eq!(integrated_loudness(&[], 1, 48_000), ::std::f64::NEG_INFINITY);
+        // Under the absolute gate counts as silence too.
+        let hiss = sine(997.0, db_to_gain(-80.0), 0.0, 2.0, 1, 48_000);
+        assert_eq!(integrated_loudness(&hiss, 1, 48_000), ::std::f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn gating_ignores_pauses() {
+        let mut samples = reference_tone(2.0, 1, 48_000);
+        samples.extend(vec![0.0; 48_000 * 4]);
+        samples.extend(reference_tone(2.0, 1, 48_000));
+
+        // The 34 blocks of tone pass, and so do the six that straddle the pause, carrying three
+        // blocks' worth of it. The 37 silent blocks between them don't count at all.
+        assert_near(integrated_loudness(&samples, 1, 48_000), -20.0 + 10.0 * (37.0f64 / 40.0).log10(), 0.01);
+    }
+
+    #[test]
+    fn relative_gate_ignores_quiet_passages() {
+        // Background 30 dB down falls under the relative gate however long it runs.
+        let mut samples = reference_tone(2.0, 1, 48_000);
+        samples.extend(sine(997.0, db_to_gain(-50.0) * 2f64.sqrt(), 0.0, 6.0, 1, 48_000));
+
+        // Only the 17 blocks of tone and the three straddling its end pass.
+        assert_near(integrated_loudness(&samples, 1, 48_000), -20.0 + 10.0 * (18.5f64 / 20.0).log10(), 0.01);
+    }
+
+    #[test]
+    fn true_peak_catches_intersample_peaks() {
+        // A quarter-rate sine 45 degrees out of phase never lands a sample on its crest.
+        let samples = sine(12_000.0, 1.0, PI / 4.0, 0.5, 1, 48_000);
+        let sample_peak = samples.iter().fold(0.0f64, |peak, &sample| peak.max((sample as f64).abs()));
+        assert_near(gain_to_db(sample_peak), -3.01, 0.01);
+
+        // Away from the ringing where the tone starts and stops abruptly.
+        let peaks = frame_true_peaks(&samples, 1, 48_000);
+        let steady = peaks[1000..peaks.len() - 1000].iter().fold(0.0f64, |peak, &frame| peak.max(frame));
+        assert_near(gain_to_db(steady), 0.0, 0.01);
+        assert!(true_peak(&samples, 1, 48_000) >= gain_to_db(steady));
+    }
+
+    #[test]
+    fn limited_gain_stays_under_the_ceiling() {
+        let ceiling_dbtp = -1.0;
+        let cases = vec![
+            // Steady tone pushed well past the ceiling.
+            reference_tone(0.3, 2, 48_000),
+            // Intersample peaks.
+            sine(12_000.0, 0.5, PI / 4.0, 0.3, 1, 48_000),
+            // A transient in the middle of quiet speech.
+            {
+                let mut samples = sine(220.0, 0.05, 0.0, 0.2, 1, 48_000);
+                samples.extend(sine(3_000.0, 0.9, 0.0, 0.01, 1, 48_000));
+                samples.extend(sine(220.0, 0.05, 0.0, 0.2, 1, 48_000));
+                samples
+            },
+        ];
+
+        for (case, mut samples) in cases.into_iter().enumerate() {
+            let channels = if case == 0 { 2 } else { 1 };
+            let limited = apply_limited_gain(&mut samples, channels, 48_000, db_to_gain(20.0), db_to_gain(ceiling_dbtp));
+            assert!(limited, "case {} should need limiting", case);
+            let peak = true_peak(&samples, channels, 48_000);
+            assert!(peak <= ceiling_dbtp + 0.01, "case {} peaks at {} dBTP", case, peak);
+        }
+    }
+
+    #[test]
+    fn unlimited_gain_is_applied_exactly() {
+        let original = reference_tone(0.3, 1, 48_000);
+        let mut samples = original.clone();
+        assert!(!apply_limited_gain(&mut samples, 1, 48_000, 0.5, 1.0));
+        for (&output, &input) in samples.iter().zip(original.iter()) {
+            assert_eq!(output, (input as f64 * 0.5) as f32);
+        }
+    }
+
+    #[test]
+    fn normalize_reaches_the_target() {
+        let mut samples = reference_tone(3.0, 2, 48_000);
+        let normalized = normalize(&mut samples, 2, 48_000, -23.0, -1.0);
+        assert_near(normalized.input_lufs, -16.99, 0.1);
+        assert_near(normalized.output_lufs, -23.0, 0.1);
+        assert_near(normalized.gain_db, -23.0 - normalized.input_lufs, 1e-9);
+        assert!(!normalized.limited);
+
+        let mut silence = vec![0.0; 48_000];
+        let normalized = normalize(&mut silence, 1, 48_000, -23.0, -1.0);
+        assert_eq!(normalized.gain_db, 0.0);
+        assert!(silence.iter().all(|&sample| sample == 0.0));
+    }
+}
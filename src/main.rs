@@ -5,13 +5,36 @@ extern crate restson;
 extern crate base64;
 extern crate tempfile;
 extern crate rodio;
-#[macro_use] extern crate clap;
+extern crate clap;
 extern crate hound;
 extern crate cpal;
-extern crate audrey;
 extern crate file;
-
-use clap::{App, Arg, ArgMatches};
+extern crate ctrlc;
+extern crate dirs;
+extern crate toml;
+#[cfg(test)] extern crate claxon;
+
+mod capture;
+mod compose;
+mod config;
+mod convert;
+mod devices;
+mod dialogue;
+mod flac;
+mod loudness;
+mod meter;
+mod operation;
+mod payload;
+mod preprocess;
+mod resample;
+mod ring;
+mod streaming;
+#[cfg(test)] mod test_signal;
+mod transcript;
+mod trim;
+mod vad;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use restson::{RestClient,RestPath,Error};
 
 use std::env;
@@ -53,6 +76,32 @@ struct AudioConfig {
     gain: f32,
 }
 
+// The ranges the service accepts; anything outside them fails the whole request.
+const PITCH_RANGE: (f32, f32) = (-20.0, 20.0);
+const RATE_RANGE: (f32, f32) = (0.25, 4.0);
+const GAIN_RANGE: (f32, f32) = (-96.0, 16.0);
+
+fn checked(what: &str, setting: &config::Setting<f32>, (min, max): (f32, f32)) -> Result<f32, String> {
+    if setting.value >= min && setting.value <= max {
+        Ok(setting.value)
+    } else {
+        Err(format!("{} must be in the range [{:?}, {:?}], not {}", what, min, max, setting))
+    }
+}
+
+impl AudioConfig {
+    /// LINEAR16 audio at the voice's pitch, rate and gain, checked here so a bad value is caught
+    /// before anything is sent, along with where it was set.
+    fn new(voice: &config::Voice) -> Result<AudioConfig, String> {
+        Ok(AudioConfig {
+            audio_encoding: String::from("LINEAR16"), // OGG_OPUS, LINEAR16, MP3
+            pitch: checked("Pitch", &voice.pitch, PITCH_RANGE)?,
+            speaking_rate: checked("Speaking rate", &voice.rate, RATE_RANGE)?,
+            gain: checked("Gain", &voice.gain, GAIN_RANGE)?,
+        })
+    }
+}
+
 #[derive(Serialize,Deserialize)]
 struct SynthesizeRequest {
     #[serde(rename = "input")]
@@ -93,6 +142,77 @@ struct RecognitionConfig {
 
     #[serde(rename = "enableWordTimeOffsets")]
     enable_word_time_offsets: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "diarizationConfig")]
+    diarization_config: Option<SpeakerDiarizationConfig>,
+
+    #[serde(rename = "enableAutomaticPunctuation")]
+    enable_automatic_punctuation: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "model")]
+    model: Option<RecognitionModel>,
+
+    #[serde(rename = "useEnhanced")]
+    use_enhanced: bool,
+
+    #[serde(rename = "audioChannelCount")]
+    audio_channel_count: i32,
+
+    #[serde(rename = "enableSeparateRecognitionPerChannel")]
+    enable_separate_recognition_per_channel: bool,
+}
+
+// https://cloud.google.com/speech-to-text/docs/reference/rest/v1/RecognitionConfig#FIELDS.model
+#[derive(Serialize,Deserialize,Clone,Copy,PartialEq)]
+enum RecognitionModel {
+    #[serde(rename = "phone_call")]
+    PhoneCall,
+
+    #[serde(rename = "video")]
+    Video,
+
+    #[serde(rename = "command_and_search")]
+    CommandAndSearch,
+
+    #[serde(rename = "default")]
+    Default,
+}
+
+impl RecognitionModel {
+    fn has_enhanced(&self) -> bool {
+        match *self {
+            RecognitionModel::PhoneCall | RecognitionModel::Video => true,
+            RecognitionModel::CommandAndSearch | RecognitionModel::Default => false,
+        }
+    }
+}
+
+impl std::str::FromStr for RecognitionModel {
+    type Err = ();
+
+    fn from_str(model: &str) -> Result<RecognitionModel, ()> {
+        match model {
+            "phone_call" => Ok(RecognitionModel::PhoneCall),
+            "video" => Ok(RecognitionModel::Video),
+            "command_and_search" => Ok(RecognitionModel::CommandAndSearch),
+            "default" => Ok(RecognitionModel::Default),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Serialize,Deserialize)]
+struct SpeakerDiarizationConfig {
+    #[serde(rename = "enableSpeakerDiarization")]
+    enable_speaker_diarization: bool,
+
+    #[serde(rename = "minSpeakerCount")]
+    min_speaker_count: i32,
+
+    #[serde(rename = "maxSpeakerCount")]
+    max_speaker_count: i32,
 }
 
 #[derive(Serialize,Deserialize)]
@@ -118,14 +238,20 @@ struct RecognizeRequest {
 
 #[derive(Deserialize)]
 struct SpeechRecognitionWordInfo {
+    #[serde(default)]
     #[serde(rename = "startTime")]
-    _start_time: String,
+    start_time: String,
 
+    #[serde(default)]
     #[serde(rename = "endTime")]
-    _end_time: String,
+    end_time: String,
 
     #[serde(rename = "word")]
-    _word: String,
+    word: String,
+
+    #[serde(default)]
+    #[serde(rename = "speakerTag")]
+    speaker_tag: i32,
 }
 
 #[derive(Deserialize)]
@@ -133,6 +259,7 @@ struct SpeechRecognitionAlternative {
     #[serde(rename = "transcript")]
     transcript: String,
 
+    #[serde(default)]
     #[serde(rename = "confidence")]
     confidence: f32,
 
@@ -146,6 +273,10 @@ struct SpeechRecognitionResult {
     #[serde(default)]
     #[serde(rename = "alternatives")]
     alternatives: Vec<SpeechRecognitionAlternative>,
+
+    #[serde(default)]
+    #[serde(rename = "channelTag")]
+    channel_tag: i32,
 }
 
 #[derive(Deserialize)]
@@ -169,23 +300,6 @@ impl RestPath<String> for RecognizeRequest {
     }
 }
 
-fn sample_format(format: cpal::SampleFormat) -> hound::SampleFormat {
-    match format {
-        cpal::SampleFormat::U16 => hound::SampleFormat::Int,
-        cpal::SampleFormat::I16 => hound::SampleFormat::Int,
-        cpal::SampleFormat::F32 => hound::SampleFormat::Float,
-    }
-}
-
-fn wav_spec_from_format(format: &cpal::Format) -> hound::WavSpec {
-    hound::WavSpec {
-        channels: format.channels as _,
-        sample_rate: format.sample_rate.0 as _,
-        bits_per_sample: (format.data_type.sample_size() * 8) as _,
-        sample_format: sample_format(format.data_type),
-    }
-}
-
 fn pause(message: &str) {
     let mut stdin = io::stdin();
     let mut stdout = io::stdout();
@@ -196,67 +310,193 @@ fn pause(message: &str) {
     let _ = stdin.read(&mut [0u8]).unwrap();
 }
 
-fn enumerate_audio() {
-    println!("Default Input Device:\n  {:?}", cpal::default_input_device().map(|e| e.name()));
-    println!("Default Output Device:\n  {:?}", cpal::default_output_device().map(|e| e.name()));
+fn enumerate_audio(json: bool) {
+    let listing = devices::listing();
+    if json {
+        println!("{}", devices::render_json(&listing));
+    } else {
+        print!("{}", devices::render_text(&listing));
+    }
+}
 
-    let devices = cpal::devices();
-    println!("Devices: ");
-    for (device_index, device) in devices.enumerate() {
-        println!("{}. \"{}\"",
-                 device_index + 1,
-                 device.name());
+// Returns the synthesized WAV file.
+fn synthesize_request(client: &mut RestClient, params: &[(&str, &str)], data: &SynthesizeRequest) -> Result<Vec<u8>, String> {
+    // https://cloudplatform.googleblog.com/2018/03/introducing-Cloud-Text-to-Speech-powered-by-Deepmind-WaveNet-technology.html
+    // https://developers.google.com/web/updates/2014/01/Web-apps-that-talk-Introduction-to-the-Speech-Synthesis-API
+    // https://cloud.google.com/speech/reference/rpc/google.cloud.speech.v1beta1
+    // https://cloud.google.com/text-to-speech/docs/reference/rest/v1beta1/text/synthesize
+    let resp: Result<SynthesizeResponse, Error> = client.post_capture_with(String::from("text:synthesize"), data, params);
+    match resp {
+        Err(err) => {
+            // Include the serialized request
+            let serialized = serde_json::to_string(data).unwrap();
+            Err(format!("Failed processing request: {:?}\nSerialized request is: {}", err, serialized))
+        },
+        Ok(val) => base64::decode(&val.audio_content).map_err(|err| format!("Failed to decode synthesized audio: {}", err)),
+    }
+}
 
-        // Input formats
-        if let Ok(fmt) = device.default_input_format() {
-            println!("  Default input stream format:\n    {:?}", fmt);
-        }
-        let mut input_formats = match device.supported_input_formats() {
-            Ok(f) => f.peekable(),
-            Err(e) => {
-                println!("Error: {:?}", e);
-                continue;
-            },
-        };
-        if input_formats.peek().is_some() {
-            println!("  All supported input stream formats:");
-            for (format_index, format) in input_formats.enumerate() {
-                println!("    {}.{}. {:?}", device_index + 1, format_index + 1, format);
-            }
-        }
+// Synthesizes each segment and mixes them together, returning the mix and where each segment
+// starts and ends in it.
+fn render_segments(api_key: &str, args: &ArgMatches, segments: &[compose::Segment]) -> Result<(compose::Mix, Vec<(f32, f32)>), String> {
+    let layers = config::load(args.value_of("config"))?;
 
-        // Output formats
-        if let Ok(fmt) = device.default_output_format() {
-            println!("  Default output stream format:\n    {:?}", fmt);
-        }
-        let mut output_formats = match device.supported_output_formats() {
-            Ok(f) => f.peekable(),
-            Err(e) => {
-                println!("Error: {:?}", e);
-                continue;
-            },
+    let mut client = RestClient::new("https://texttospeech.googleapis.com").unwrap();
+
+    // https://cloud.google.com/storage/docs/json_api/v1/how-tos/authorizing
+    let params = vec![("key", api_key)];
+
+    // Check every segment's voice before paying for any synthesis.
+    let mut requests = Vec::with_capacity(segments.len());
+    for (index, segment) in segments.iter().enumerate() {
+        // A segment's preset takes the place of --preset, beneath every other layer; its own
+        // settings go on top of them all, the environment included.
+        let settings = config::VoiceSettings {
+            name: segment.voice.clone(),
+            language: segment.language.clone(),
+            gender: segment.gender.clone(),
+            rate: segment.rate,
+            pitch: segment.pitch,
+            gain: segment.gain,
         };
-        if output_formats.peek().is_some() {
-            println!("  All supported output stream formats:");
-            for (format_index, format) in output_formats.enumerate() {
-                println!("    {}.{}. {:?}", device_index + 1, format_index + 1, format);
-            }
-        }
+        let preset = segment.preset.as_ref().map(|preset| preset.as_str()).or_else(|| args.value_of("preset"));
+        let voice = layers.resolve(preset, &settings, "script").map_err(|err| format!("Segment {}: {}", index + 1, err))?;
+        let audio_config = AudioConfig::new(&voice).map_err(|err| format!("Segment {}: {}", index + 1, err))?;
+
+        requests.push(SynthesizeRequest {
+            input: InputConfig {
+                ssml: Some(segment.text.clone()),
+                text: None,
+            },
+            voice: VoiceConfig {
+                language_code: voice.language.value,
+                name: voice.name.value,
+                gender: voice.gender.value,
+            },
+            audio_config: audio_config,
+        });
+    }
+
+    let mut mix = compose::Mix::new();
+    let mut times = Vec::with_capacity(segments.len());
+    for (index, (segment, data)) in segments.iter().zip(requests.iter()).enumerate() {
+        println!("Synthesizing segment {}/{}: {}", index + 1, segments.len(), segment.text);
+
+        let wav = synthesize_request(&mut client, &params, data)?;
+        let (spec, samples) = convert::decode_wav(&wav).map_err(|err| format!("Failed to decode segment {}: {}", index + 1, err))?;
+        times.push(mix.append(spec, &samples, &segment.join()?));
     }
+
+    Ok((mix, times))
+}
+
+fn voice_settings(args: &ArgMatches) -> Result<config::VoiceSettings, String> {
+    Ok(config::VoiceSettings {
+        name: args.value_of("name").map(String::from),
+        language: args.value_of("language").map(String::from),
+        gender: args.value_of("gender").map(String::from),
+        rate: optional_value(args, "rate")?,
+        pitch: optional_value(args, "pitch")?,
+        gain: optional_value(args, "gain")?,
+    })
 }
 
-fn synthesize(args: &ArgMatches) {
+fn voice_config(args: &ArgMatches) -> Result<config::Voice, String> {
+    let layers = config::load(args.value_of("config"))?;
+    layers.resolve(args.value_of("preset"), &voice_settings(args)?, "command line")
+}
+
+fn config_show(args: &ArgMatches) -> Result<(), String> {
+    let layers = config::load(args.value_of("config"))?;
+    let voice = layers.resolve(args.value_of("preset"), &voice_settings(args)?, "command line")?;
+
+    match layers.path() {
+        Some(path) => println!("config:   {:?}", path),
+        None => println!("config:   (none)"),
+    }
+    println!("preset:   {}", voice.preset.as_ref().map(|preset| preset.as_str()).unwrap_or("(none)"));
+    println!("name:     {}", voice.name);
+    println!("language: {}", voice.language);
+    println!("gender:   {}", voice.gender);
+    println!("rate:     {}", voice.rate);
+    println!("pitch:    {}", voice.pitch);
+    println!("gain:     {}", voice.gain);
+
+    AudioConfig::new(&voice)?;
+    Ok(())
+}
+
+fn compose(args: &ArgMatches) -> Result<(), String> {
+    let api_key = args.value_of("key").unwrap();
+    let output_path = args.value_of("output").unwrap();
+
+    let script = compose::load(args.value_of("script").unwrap())?;
+    let (mix, _) = render_segments(api_key, args, &script.segments)?;
+
+    mix.write(output_path).map_err(|err| format!("Failed to write {:?}: {}", output_path, err))?;
+    println!("Composed {} segments to: {:?}", script.segments.len(), output_path);
+    Ok(())
+}
+
+fn dialogue(args: &ArgMatches) -> Result<(), String> {
+    let api_key = args.value_of("key").unwrap();
+    let script_path = args.value_of("script").unwrap();
+    let output_path = std::path::PathBuf::from(args.value_of("output").unwrap());
+    let cues_path = match args.value_of("cues") {
+        Some(path) => std::path::PathBuf::from(path),
+        None => output_path.with_extension("cues.json"),
+    };
+
+    let gap = match optional_value::<f32>(args, "gap")? {
+        Some(gap) if !(gap >= 0.0 && gap.is_finite()) => return Err(format!("--gap must be zero or more seconds, not {}", gap)),
+        gap => gap.unwrap_or(0.3),
+    };
+
+    let script = file::get_text(script_path).map_err(|err| format!("Failed to read {:?}: {}", script_path, err))?;
+    let dialogue = dialogue::parse(&script, gap)?;
+
+    let (mix, times) = render_segments(api_key, args, &dialogue.segments)?;
+
+    mix.write(&output_path).map_err(|err| format!("Failed to write {:?}: {}", output_path, err))?;
+    file::put_text(&cues_path, dialogue::render_cues(&dialogue.lines, &times)).map_err(|err| format!("Failed to write {:?}: {}", cues_path, err))?;
+    println!("Rendered {} lines to: {:?}, cue sheet: {:?}", dialogue.lines.len(), output_path, cues_path);
+    Ok(())
+}
+
+fn synthesize(args: &ArgMatches) -> Result<(), String> {
     let api_key = args.value_of("key").unwrap();
 
     let synthesize_input = args.value_of("input").unwrap();
     println!("Synthesizing input text: {}", synthesize_input);
 
-    let pitch = value_t!(args, "pitch", f32).unwrap_or(0.00);
-    let gain = value_t!(args, "gain", f32).unwrap_or(0.00);
-    let speaking_rate = value_t!(args, "rate", f32).unwrap_or(1.00);
-    let gender = args.value_of("gender").unwrap_or("MALE");
-    let language = args.value_of("language").unwrap_or("en-US");
-    let voice_name = args.value_of("name").unwrap_or("en-US-Wavenet-D");
+    let voice = voice_config(args)?;
+    let audio_config = AudioConfig::new(&voice)?;
+
+    let volume = match optional_value::<f32>(args, "volume")? {
+        Some(volume) if !(volume >= 0.0 && volume <= 2.0) => {
+            return Err(format!("Playback volume must be in the range [0.0, 2.0], not {}", volume));
+        },
+        volume => volume.unwrap_or(1.0),
+    };
+
+    let trimming = if args.is_present("trim") {
+        Some(trim_options(args)?)
+    } else {
+        None
+    };
+
+    let loudness_target = loudness_target(args)?;
+
+    // Resolve the playback device up front rather than after paying for synthesis.
+    let endpoint = if args.is_present("play") {
+        let endpoint = match args.value_of("output-device") {
+            Some(selector) => devices::find_output_endpoint(selector),
+            None => rodio::default_endpoint().ok_or_else(|| String::from("No default output device")),
+        };
+        Some(endpoint?)
+    } else {
+        None
+    };
 
     let mut client = RestClient::new("https://texttospeech.googleapis.com").unwrap();
 
@@ -269,33 +509,36 @@ fn synthesize(args: &ArgMatches) {
             text: None,
         },
         voice: VoiceConfig {
-            language_code: String::from(language), // https://cloud.google.com/speech/docs/languages
-            name: String::from(voice_name), // en-US-Wavenet-C (female)
-            gender: String::from(gender), // MALE, FEMALE, NEUTRAL
-        },
-        audio_config: AudioConfig {
-            audio_encoding: String::from("LINEAR16"), // OGG_OPUS, LINEAR16, MP3
-            pitch: pitch,
-            gain: gain,
-            speaking_rate: speaking_rate,
+            language_code: voice.language.value, // https://cloud.google.com/speech/docs/languages
+            name: voice.name.value, // en-US-Wavenet-C (female)
+            gender: voice.gender.value, // MALE, FEMALE, NEUTRAL
         },
+        audio_config: audio_config,
     };
 
-    // https://cloudplatform.googleblog.com/2018/03/introducing-Cloud-Text-to-Speech-powered-by-Deepmind-WaveNet-technology.html
-    // https://developers.google.com/web/updates/2014/01/Web-apps-that-talk-Introduction-to-the-Speech-Synthesis-API
-    // https://cloud.google.com/speech/reference/rpc/google.cloud.speech.v1beta1
-    // https://cloud.google.com/text-to-speech/docs/reference/rest/v1beta1/text/synthesize
-    let resp: Result<SynthesizeResponse, Error> = client.post_capture_with(String::from("text:synthesize"), &data, &params);
-    match resp {
-        Err(err) => {
-            println!("Failed processing request: {:?}", err);
+    match synthesize_request(&mut client, &params, &data) {
+        Err(err) => Err(err),
+        Ok(mut bytes_vec) => {
+
+            // volumeGainDb is a fixed offset, so different voices and rates still come out at
+            // different loudness; measuring and normalizing the result makes prompts consistent.
+            if let Some((target_lufs, ceiling_dbtp)) = loudness_target {
+                match loudness::normalize_wav(&bytes_vec, target_lufs, ceiling_dbtp) {
+                    Ok((normalized_bytes, normalized)) => {
+                        println!("Normalized loudness from {:.1} to {:.1} LUFS ({:+.1} dB{}), true peak {:.1} dBTP",
+                                 normalized.input_lufs,
+                                 normalized.output_lufs,
+                                 normalized.gain_db,
+                                 if normalized.limited { ", limited" } else { "" },
+                                 normalized.true_peak_dbtp);
+                        bytes_vec = normalized_bytes;
+                    },
+                    Err(err) => {
+                        return Err(format!("Failed to normalize synthesized audio: {}", err));
+                    },
+                }
+            }
 
-            // Print out serialized request
-            let serialized = serde_json::to_string(&data).unwrap();
-            println!("Serialized request is: {}", serialized);
-        },
-        Ok(val) => {
-            let bytes_vec = base64::decode(&val.audio_content).unwrap();
             let bytes = bytes_vec.as_slice();
 
             let mut tmpfile = tempfile::NamedTempFile::new().unwrap();
@@ -306,189 +549,770 @@ fn synthesize(args: &ArgMatches) {
             tmpfile.persist(&play_path).unwrap();
             println!("Persisted response data to: {:?}", play_path);
 
-            if args.is_present("play") {
-                println!("Playing synthesized audio");
+            let play_path = match trimming {
+                Some(options) => trim_audio(&play_path, &env::temp_dir().join("speech-test-trimmed.wav"), &options)?,
+                None => play_path,
+            };
+
+            if let Some(endpoint) = endpoint {
+                println!("Playing synthesized audio on \"{}\"", endpoint.name());
 
-                let endpoint = rodio::default_endpoint().unwrap();
                 let mut sink = rodio::Sink::new(&endpoint);
                 let play_file = std::fs::File::open(&play_path).unwrap();
                 sink.append(rodio::Decoder::new(BufReader::new(play_file)).unwrap());
-                sink.set_volume(1.0);
+                sink.set_volume(volume);
                 sink.sleep_until_end();
             }
+
+            Ok(())
+        }
+    }
+}
+
+struct RecordLimits {
+    // Record for exactly this long, without waiting for enter.
+    duration: Option<std::time::Duration>,
+
+    // Start right away and record until signalled rather than waiting for enter.
+    hands_free: bool,
+
+    // Safety cap so an unattended recording can't fill the disk.
+    max_duration: std::time::Duration,
+
+    // Start when speech is detected and stop after trailing silence.
+    vad: Option<vad::VadConfig>,
+}
+
+// SIGINT and SIGTERM (and optionally enter) request the end of a recording, so that the WAV
+// is finalized cleanly instead of being left truncated.
+fn stop_requests(listen_for_enter: bool) -> (std::sync::mpsc::Sender<&'static str>, std::sync::mpsc::Receiver<&'static str>) {
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    if listen_for_enter {
+        let sender = sender.clone();
+        std::thread::spawn(move || {
+            // On a line of its own, since the level meter redraws the current one.
+            println!("Press enter to finish recording...");
+            pause("");
+            sender.send("enter pressed").ok();
+        });
+    }
+
+    // Once the recording is over nobody is listening, so signals exit as usual.
+    let signal_sender = sender.clone();
+    ctrlc::set_handler(move || {
+        if signal_sender.send("signal received").is_err() {
+            std::process::exit(130);
+        }
+    }).expect("Failed to install signal handler");
+
+    (sender, receiver)
+}
+
+// Passes captured samples through the voice gate, if there is one, reporting when speech starts
+// and requesting a stop once it ends.
+fn gate_samples<'a, T: cpal::Sample>(gate: &mut Option<vad::Gate<T>>, samples: &'a [T], gated: &'a mut Vec<T>, stop: &std::sync::mpsc::Sender<&'static str>) -> &'a [T] {
+    match *gate {
+        None => samples,
+        Some(ref mut gate) => {
+            gated.clear();
+            match gate.process(samples, gated) {
+                Some(vad::VadEvent::SpeechStarted) => println!("Speech detected, recording..."),
+                Some(vad::VadEvent::SpeechEnded) => {
+                    stop.send("trailing silence").ok();
+                },
+                None => (),
+            }
+            gated
+        },
+    }
+}
+
+// How much captured audio can queue up behind a slow disk before samples are dropped.
+const CAPTURE_BUFFER_SECS: usize = 2;
+
+// Frames taken off the capture queue at a time; whole frames, so the gate and meter stay aligned.
+const CAPTURE_BLOCK_FRAMES: usize = 2048;
+
+// How long to wait for the audio callback to notice a stop, in case the stream died instead.
+const CAPTURE_STOP_TIMEOUT_MS: u64 = 500;
+
+fn record_audio(record_path: &std::path::PathBuf, device: &cpal::Device, format: &cpal::Format, limits: &RecordLimits) -> meter::Summary {
+    println!("Recording from \"{}\", input format: {:?}", device.name(), format);
+
+    let levels = capture(record_path, device, format, limits);
+    println!("Recording {:?} complete!", &record_path);
+    levels.report();
+    levels
+}
+
+// Captured audio on its way from the queue through the voice gate and level meter to the file,
+// all off the audio thread.
+struct CaptureWriter {
+    consumer: ring::Consumer<i16>,
+    writer: hound::WavWriter<io::BufWriter<std::fs::File>>,
+    meter: meter::LevelMeter,
+    gate: Option<vad::Gate<i16>>,
+    stop: std::sync::mpsc::Sender<&'static str>,
+    block: Vec<i16>,
+    gated: Vec<i16>,
+}
+
+impl CaptureWriter {
+    // Writes everything queued so far, returning how many samples that was.
+    fn drain(&mut self) -> Result<usize, hound::Error> {
+        let mut drained = 0;
+        loop {
+            let count = self.consumer.pop(&mut self.block);
+            if count == 0 {
+                return Ok(drained);
+            }
+            drained += count;
+
+            let samples = gate_samples(&mut self.gate, &self.block[..count], &mut self.gated, &self.stop);
+            for &sample in samples {
+                self.writer.write_sample(sample)?;
+            }
+            self.meter.process(samples);
         }
     }
 }
 
-fn record_audio(record_path: &std::path::PathBuf) {
-    // Setup the default input device and stream with the default input format.
-    let device = cpal::default_input_device().expect("Failed to get default input device");
-    let format = device.default_input_format().expect("Failed to get default input format");
+// Drains captured samples into the WAV file until recording stops, then finalizes it.
+fn write_captured(mut capture: CaptureWriter, recording: std::sync::Arc<std::sync::atomic::AtomicBool>, stream_stopped: std::sync::Arc<std::sync::atomic::AtomicBool>) -> Result<(ring::Overruns, meter::Summary), hound::Error> {
+    let mut stopping_since = None;
+    loop {
+        // Once the callback has seen the stop it queues nothing more, so this pass gets the rest.
+        let stream_done = stream_stopped.load(std::sync::atomic::Ordering::Acquire);
+        let drained = capture.drain()?;
+        if stream_done {
+            break;
+        }
 
-    println!("Recording input format: {:?}", format);
+        if !recording.load(std::sync::atomic::Ordering::Relaxed) {
+            let since = *stopping_since.get_or_insert_with(std::time::Instant::now);
+            if since.elapsed() >= std::time::Duration::from_millis(CAPTURE_STOP_TIMEOUT_MS) {
+                break;
+            }
+        }
+        if drained == 0 {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    capture.writer.finalize()?;
+    Ok((capture.consumer.overruns(), capture.meter.summary()))
+}
 
+fn capture(record_path: &std::path::PathBuf, device: &cpal::Device, format: &cpal::Format, limits: &RecordLimits) -> meter::Summary {
     let event_loop = cpal::EventLoop::new();
-    let stream_id = event_loop.build_input_stream(&device, &format)
+    let stream_id = event_loop.build_input_stream(device, format)
         .expect("Failed to build input stream");
     event_loop.play_stream(stream_id);
 
-    let spec = wav_spec_from_format(&format);
+    let spec = capture::output_spec(format);
     //println!("spec - channels:{} sample_rate:{} bits_per_sample:{}", spec.channels, spec.sample_rate, spec.bits_per_sample);
 
     let writer = hound::WavWriter::create(&record_path, spec).unwrap();
-    let writer = std::sync::Arc::new(std::sync::Mutex::new(Some(writer)));
 
-    pause("Press enter to start recording...");
+    let unattended = limits.hands_free || limits.duration.is_some() || limits.vad.is_some();
+    if !unattended {
+        pause("Press enter to start recording...");
+    }
+    let (stop_sender, stop) = stop_requests(!unattended);
     let recording = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
 
-    // Run the input stream on a separate thread.
-    let writer_2 = writer.clone();
+    // The audio thread only converts and queues samples; a separate thread gates, meters and
+    // writes them to disk, so neither a slow disk nor a contended lock can stall or drop a
+    // callback.
+    let capacity = format.sample_rate.0 as usize * format.channels as usize * CAPTURE_BUFFER_SECS;
+    let (mut producer, consumer) = ring::channel::<i16>(capacity);
+    let capture_writer = CaptureWriter {
+        consumer: consumer,
+        writer: writer,
+        meter: meter::LevelMeter::new(format.channels, format.sample_rate.0),
+        gate: limits.vad.as_ref().map(|config| vad::Gate::new(config, format.channels, format.sample_rate.0)),
+        stop: stop_sender,
+        block: vec![0; CAPTURE_BLOCK_FRAMES * format.channels as usize],
+        gated: Vec::with_capacity(CAPTURE_BLOCK_FRAMES * format.channels as usize),
+    };
+    let stream_stopped = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     let recording_2 = recording.clone();
+    let stream_stopped_2 = stream_stopped.clone();
+    let writer_thread = std::thread::spawn(move || write_captured(capture_writer, recording_2, stream_stopped_2));
+
+    // Sized up front so converting a callback's buffer never allocates.
+    let mut samples = Vec::with_capacity(capacity);
+
+    // Run the input stream on a separate thread.
+    let recording_3 = recording.clone();
     std::thread::spawn(move || {
         event_loop.run(move |_, data| {
-            // If we're done recording, return early.
-            if !recording_2.load(std::sync::atomic::Ordering::Relaxed) {
+            // If we're done recording, let the writer know nothing more is coming.
+            if !recording_3.load(std::sync::atomic::Ordering::Relaxed) {
+                stream_stopped.store(true, std::sync::atomic::Ordering::Release);
                 return;
             }
-            // Otherwise write to the wav writer.
+
+            samples.clear();
             match data {
-                cpal::StreamData::Input { buffer: cpal::UnknownTypeInputBuffer::U16(buffer) } => {
-                    if let Ok(mut guard) = writer_2.try_lock() {
-                        if let Some(writer) = guard.as_mut() {
-                            for sample in buffer.iter() {
-                                let sample = cpal::Sample::to_i16(sample);
-                                writer.write_sample(sample).ok();
-                            }
-                        }
-                    }
-                },
-                cpal::StreamData::Input { buffer: cpal::UnknownTypeInputBuffer::I16(buffer) } => {
-                    if let Ok(mut guard) = writer_2.try_lock() {
-                        if let Some(writer) = guard.as_mut() {
-                            for &sample in buffer.iter() {
-                                writer.write_sample(sample).ok();
-                            }
-                        }
-                    }
-                },
-                cpal::StreamData::Input { buffer: cpal::UnknownTypeInputBuffer::F32(buffer) } => {
-                    if let Ok(mut guard) = writer_2.try_lock() {
-                        if let Some(writer) = guard.as_mut() {
-                            for &sample in buffer.iter() {
-                                writer.write_sample(sample).ok();
-                            }
-                        }
-                    }
-                },
-                _ => (),
+                cpal::StreamData::Input { buffer } => capture::normalize(&buffer, &mut samples),
+                _ => return,
             }
+
+            // Otherwise queue for the writer thread.
+            producer.push(&samples);
         });
     });
 
-    pause("Press enter to finish recording...");
-    //std::thread::sleep(std::time::Duration::from_secs(3));
+    let limit = match limits.duration {
+        Some(duration) => {
+            println!("Recording for {:.1}s...", duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9);
+            std::cmp::min(duration, limits.max_duration)
+        },
+        None => {
+            if limits.vad.is_some() {
+                println!("Waiting for speech...");
+            } else if limits.hands_free {
+                println!("Recording until interrupted...");
+            }
+            limits.max_duration
+        },
+    };
+
+    let reason = match stop.recv_timeout(limit) {
+        Ok(reason) => reason,
+        Err(_) if limits.duration.is_some() && limit < limits.max_duration => "duration reached",
+        Err(_) => "maximum duration reached",
+    };
+    // Below the level meter's line.
+    println!("\nStopping recording: {}", reason);
+
     recording.store(false, std::sync::atomic::Ordering::Relaxed);
-    writer.lock().unwrap().take().unwrap().finalize().unwrap();
-    println!("Recording {:?} complete!", &record_path);
+    let (overruns, levels) = match writer_thread.join().unwrap() {
+        Ok(result) => result,
+        Err(err) => panic!("Failed to write {:?}: {}", record_path, err),
+    };
+    println!("Capture overruns: {} ({} samples dropped)", overruns.events, overruns.samples);
+    levels
+}
+
+fn downmix(samples: &[i16], channels: usize) -> Vec<i16> {
+    samples.chunks(channels)
+        .map(|frame| {
+            let sum: i32 = frame.iter().map(|&sample| sample as i32).sum();
+            (sum / frame.len() as i32) as i16
+        })
+        .collect()
 }
 
-fn convert_audio(record_path: &std::path::PathBuf) {
+fn stream_audio(format: &cpal::Format, device: &cpal::Device, recording: std::sync::Arc<std::sync::atomic::AtomicBool>) -> std::sync::mpsc::Receiver<Vec<i16>> {
+    let event_loop = cpal::EventLoop::new();
+    let stream_id = event_loop.build_input_stream(device, format)
+        .expect("Failed to build input stream");
+    event_loop.play_stream(stream_id);
+
+    let channels = format.channels as usize;
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    // Run the input stream on a separate thread, handing each buffer over as soon as it arrives.
+    std::thread::spawn(move || {
+        let mut sender = Some(sender);
+        let mut samples = Vec::new();
+        event_loop.run(move |_, data| {
+            // Once we're done recording, drop the sender so the session sees the end of the audio.
+            if !recording.load(std::sync::atomic::Ordering::Relaxed) {
+                sender.take();
+                return;
+            }
+            samples.clear();
+            match data {
+                cpal::StreamData::Input { buffer } => capture::normalize(&buffer, &mut samples),
+                _ => return,
+            }
+            let chunk = downmix(&samples, channels);
+            if let Some(ref sender) = sender {
+                sender.send(chunk).ok();
+            }
+        });
+    });
+
+    receiver
+}
+
+fn stream_recognize(args: &ArgMatches) -> Result<(), String> {
+    let address = match args.value_of("stream-endpoint") {
+        Some(address) => address,
+        None => return Err(String::from("Streaming recognition requires --stream-endpoint <host:port>")),
+    };
 
+    let mut transport = streaming::TcpTransport::connect(address)
+        .map_err(|err| format!("Failed connecting to streaming endpoint {}: {:?}", address, err))?;
+
+    let (device, format) = devices::select_input(args.value_of("input-device"), args.value_of("input-format"))?;
+
+    println!("Streaming from \"{}\", input format: {:?}", device.name(), format);
+
+    let config = recognition_config(args, "LINEAR16", format.sample_rate.0, 1)?;
+
+    let config = streaming::StreamingRecognitionConfig {
+        config: config,
+        single_utterance: false,
+        interim_results: true,
+    };
+
+    pause("Press enter to start streaming...");
+    let recording = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let chunks = stream_audio(&format, &device, recording.clone());
+
+    // Enter stops the capture while transcripts keep printing on this thread.
+    std::thread::spawn(move || {
+        pause("");
+        recording.store(false, std::sync::atomic::Ordering::Relaxed);
+    });
+
+    println!("Streaming, press enter to finish...");
+    streaming::run_session(&mut transport, config, chunks).map_err(|err| format!("Streaming recognition failed: {:?}", err))?;
+    Ok(())
+}
+
+fn convert_audio(record_path: &std::path::PathBuf, convert_path: &std::path::PathBuf, separate_channels: bool, sample_rate: u32) -> Result<hound::WavSpec, String> {
+    let source_spec = hound::WavReader::open(&record_path).map_err(|err| format!("Failed to read recording: {}", err))?.spec();
+
+    // Two-party calls carry each party on its own channel, so only downmix when asked to.
     let record_spec = hound::WavSpec {
-        channels: 1,
-        sample_rate: 44_100,//spec.sample_rate,
+        channels: if separate_channels { source_spec.channels } else { 1 },
+        sample_rate: sample_rate,
         bits_per_sample: 16,
         sample_format: hound::SampleFormat::Int,
     };
 
-    let mut wav = audrey::open(&record_path).unwrap();
-    let buffer = wav.frames::<[i16; 2]>()
-        .map(Result::unwrap)
-        .map(|f| f)
-        .collect::<Vec<_>>();
+    if let Err(err) = convert::convert(&record_path, &convert_path, record_spec) {
+        return Err(format!("Failed to convert recording: {}", err));
+    }
+
+    println!("Converted {}-channel {} Hz {}-bit {:?} recording to {}-channel {} Hz 16-bit Int",
+             source_spec.channels, source_spec.sample_rate, source_spec.bits_per_sample, source_spec.sample_format,
+             record_spec.channels, record_spec.sample_rate);
+    Ok(record_spec)
+}
+
+fn seconds_value(args: &ArgMatches, name: &str) -> Result<Option<std::time::Duration>, String> {
+    match optional_value::<f64>(args, name)? {
+        Some(secs) if !(secs > 0.0 && secs.is_finite()) => Err(format!("--{} must be a positive number of seconds, not {}", name, secs)),
+        Some(secs) => Ok(Some(std::time::Duration::new(secs.trunc() as u64, (secs.fract() * 1e9) as u32))),
+        None => Ok(None),
+    }
+}
+
+// Returns None when no preprocessing stage was asked for.
+fn preprocess_options(args: &ArgMatches, sample_rate: u32) -> Result<Option<preprocess::Options>, String> {
+    let high_pass_hz = optional_value::<f32>(args, "high-pass")?;
+    if let Some(cutoff_hz) = high_pass_hz {
+        if !(cutoff_hz > 0.0 && cutoff_hz < sample_rate as f32 / 2.0) {
+            return Err(format!("--high-pass must be between 0 and {} Hz, not {}", sample_rate / 2, cutoff_hz));
+        }
+    }
+
+    let gate_threshold_db = optional_value::<f32>(args, "noise-gate")?;
+    if let Some(threshold_db) = gate_threshold_db {
+        if !(threshold_db < 0.0) {
+            return Err(format!("--noise-gate must be below 0 dBFS, not {}", threshold_db));
+        }
+    }
+
+    let target_db = optional_value::<f32>(args, "normalize-target")?;
+    if let Some(target_db) = target_db {
+        if !(target_db <= 0.0) {
+            return Err(format!("--normalize-target can't be above 0 dBFS, not {}", target_db));
+        }
+    }
+    let normalize = match args.value_of("normalize") {
+        Some("peak") => Some(preprocess::Normalize::Peak(target_db.unwrap_or(-1.0))),
+        Some("rms") => Some(preprocess::Normalize::Rms(target_db.unwrap_or(-20.0))),
+        _ if target_db.is_some() => return Err(String::from("--normalize-target requires --normalize")),
+        _ => None,
+    };
+
+    let options = preprocess::Options {
+        remove_dc: args.is_present("remove-dc"),
+        high_pass_hz: high_pass_hz,
+        gate_threshold_db: gate_threshold_db,
+        normalize: normalize,
+    };
+
+    if !options.remove_dc && options.high_pass_hz.is_none() && options.gate_threshold_db.is_none() && options.normalize.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(options))
+}
+
+// Returns the target loudness and true-peak ceiling, if --target-lufs was given.
+fn loudness_target(args: &ArgMatches) -> Result<Option<(f64, f64)>, String> {
+    let ceiling_dbtp = optional_value::<f64>(args, "true-peak")?;
+    if let Some(ceiling_dbtp) = ceiling_dbtp {
+        if !(ceiling_dbtp <= 0.0 && ceiling_dbtp >= -20.0) {
+            return Err(format!("--true-peak must be in the range [-20.0, 0.0] dBTP, not {}", ceiling_dbtp));
+        }
+    }
+
+    match optional_value::<f64>(args, "target-lufs")? {
+        Some(target_lufs) if !(target_lufs > -70.0 && target_lufs < 0.0) => {
+            Err(format!("--target-lufs must be between -70 and 0 LUFS, not {}", target_lufs))
+        },
+        Some(target_lufs) => Ok(Some((target_lufs, ceiling_dbtp.unwrap_or(-1.0)))),
+        None if ceiling_dbtp.is_some() => Err(String::from("--true-peak requires --target-lufs")),
+        None => Ok(None),
+    }
+}
+
+fn trim_options(args: &ArgMatches) -> Result<trim::TrimOptions, String> {
+    let mut options = trim::TrimOptions::default();
+    if let Some(threshold_db) = optional_value::<f32>(args, "trim-threshold")? {
+        if !(threshold_db < 0.0) {
+            return Err(format!("--trim-threshold must be below 0 dBFS, not {}", threshold_db));
+        }
+        options.threshold_db = threshold_db;
+    }
+    if let Some(padding_secs) = optional_value::<f32>(args, "trim-padding")? {
+        if !(padding_secs >= 0.0 && padding_secs.is_finite()) {
+            return Err(format!("--trim-padding must be zero or more seconds, not {}", padding_secs));
+        }
+        options.padding_secs = padding_secs;
+    }
+    Ok(options)
+}
+
+// Returns the path of the trimmed audio.
+fn trim_audio(path: &std::path::PathBuf, trimmed_path: &std::path::PathBuf, options: &trim::TrimOptions) -> Result<std::path::PathBuf, String> {
+    match trim::trim(path, trimmed_path, options) {
+        Ok(ref trimmed) if trimmed.silent => println!("No audio above {} dBFS in {:?}; left untrimmed", options.threshold_db, path),
+        Ok(trimmed) => println!("Trimmed {:.2}s of leading and {:.2}s of trailing silence to: {:?}", trimmed.leading_secs, trimmed.trailing_secs, trimmed_path),
+        Err(err) => return Err(format!("Failed to trim {:?}: {}", path, err)),
+    }
+    Ok(trimmed_path.clone())
+}
+
+fn trim_command(args: &ArgMatches) -> Result<(), String> {
+    let options = trim_options(args)?;
+
+    let input = std::path::PathBuf::from(args.value_of("input").unwrap());
+    let output = std::path::PathBuf::from(args.value_of("output").unwrap());
+    trim_audio(&input, &output, &options)?;
+    Ok(())
+}
+
+fn record_limits(args: &ArgMatches) -> Result<RecordLimits, String> {
+    let duration = seconds_value(args, "duration")?;
+    let max_duration = seconds_value(args, "max-duration")?.unwrap_or(std::time::Duration::from_secs(4 * 60 * 60));
+
+    if let Some(duration) = duration {
+        if duration > max_duration {
+            return Err(String::from("--duration can't exceed --max-duration"));
+        }
+    }
+
+    let vad = if args.is_present("vad") {
+        let mut config = vad::VadConfig::default();
+        if let Some(threshold_db) = optional_value::<f32>(args, "vad-threshold")? {
+            if !(threshold_db < 0.0) {
+                return Err(format!("--vad-threshold must be below 0 dBFS, not {}", threshold_db));
+            }
+            config.threshold_db = threshold_db;
+        }
+        if let Some(silence) = seconds_value(args, "vad-silence")? {
+            config.trailing_silence_secs = silence.as_secs() as f32 + silence.subsec_nanos() as f32 * 1e-9;
+        }
+        if let Some(pre_roll) = optional_value::<f32>(args, "vad-pre-roll")? {
+            if !(pre_roll >= 0.0 && pre_roll <= 5.0) {
+                return Err(format!("--vad-pre-roll must be between 0 and 5 seconds, not {}", pre_roll));
+            }
+            config.pre_roll_secs = pre_roll;
+        }
+        Some(config)
+    } else {
+        None
+    };
+
+    Ok(RecordLimits {
+        duration: duration,
+        hands_free: args.is_present("hands-free"),
+        max_duration: max_duration,
+        vad: vad,
+    })
+}
 
-    let mut writer = hound::WavWriter::create(&record_path, record_spec).unwrap();
-    for sample in buffer.iter() {
-        let sample_left  = sample[0] as f64;
-        let sample_right = sample[1] as f64;
-        writer.write_sample(((sample_left + sample_right) / 2.0) as i16).unwrap();
+fn recognition_client(args: &ArgMatches) -> RestClient {
+    let endpoint = args.value_of("speech-endpoint").unwrap_or("https://speech.googleapis.com");
+    RestClient::new(endpoint).unwrap()
+}
+
+fn optional_value<T: std::str::FromStr>(args: &ArgMatches, name: &str) -> Result<Option<T>, String> {
+    match args.value_of(name) {
+        None => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(|_| format!("Invalid value for --{}: {}", name, value)),
     }
+}
 
-    println!("{}", "Playback output format: Format { channels: 1, sample_rate: SampleRate(44100), data_type: I16 }");
+fn recognition_config(args: &ArgMatches, encoding: &str, sample_rate_hz: u32, channels: u16) -> Result<RecognitionConfig, String> {
+    let separate_channels = args.is_present("separate-channels");
+    if separate_channels && channels < 2 {
+        return Err(String::from("Separate recognition per channel requires multi-channel audio"));
+    }
+
+    let diarization_config = if args.is_present("diarize") {
+        let min_speaker_count = optional_value(args, "min-speakers")?.unwrap_or(2);
+        let max_speaker_count = optional_value(args, "max-speakers")?.unwrap_or(6);
+        if min_speaker_count < 1 || max_speaker_count < min_speaker_count {
+            return Err(format!("Invalid speaker count range [{}, {}]", min_speaker_count, max_speaker_count));
+        }
+
+        Some(SpeakerDiarizationConfig {
+            enable_speaker_diarization: true,
+            min_speaker_count: min_speaker_count,
+            max_speaker_count: max_speaker_count,
+        })
+    } else {
+        None
+    };
+
+    let model: Option<RecognitionModel> = optional_value(args, "model")?;
+    let use_enhanced = args.is_present("enhanced");
+    if use_enhanced {
+        // The service silently falls back to the standard model when no enhanced one exists.
+        if let Some(model) = model {
+            if !model.has_enhanced() {
+                return Err(format!("No enhanced version of the '{}' model exists; enhanced models are phone_call and video", args.value_of("model").unwrap()));
+            }
+        }
+    }
+
+    // Speaker turns and subtitle cues are both built from per-word time offsets.
+    let enable_word_time_offsets = diarization_config.is_some() || args.value_of("output-format") == Some("srt");
+
+    Ok(RecognitionConfig {
+        encoding: String::from(encoding),
+        sample_rate_hz: sample_rate_hz as f32,
+        language: String::from("en-US"),
+        max_alternatives: 0,
+        profanity_filter: false,
+        contexts: vec![],
+        enable_word_time_offsets: enable_word_time_offsets,
+        diarization_config: diarization_config,
+        enable_automatic_punctuation: args.is_present("punctuation"),
+        model: model,
+        use_enhanced: use_enhanced,
+        audio_channel_count: channels as i32,
+        enable_separate_recognition_per_channel: separate_channels,
+    })
+}
+
+fn print_recognition(args: &ArgMatches, val: RecognizeResponse) {
+    println!("Length of results is {}", val.results.len());
+
+    let turns = transcript::turns(&val);
+    match args.value_of("output-format").unwrap_or("text") {
+        "json" => println!("{}", transcript::render_json(&turns)),
+        "srt" => print!("{}", transcript::render_srt(&turns)),
+        _ => print!("Recognition result:\n{}", transcript::render_text(&turns)),
+    }
 }
 
-fn recognize(args: &ArgMatches) {
+fn poll_timeout(args: &ArgMatches) -> Result<Option<std::time::Duration>, String> {
+    Ok(optional_value(args, "poll-timeout")?.map(std::time::Duration::from_secs))
+}
+
+fn poll_operation(args: &ArgMatches, client: &mut RestClient, name: &str, timeout: Option<std::time::Duration>) -> Result<(), String> {
+    let api_key = args.value_of("key").unwrap();
+    let params = vec![("key", api_key)];
+
+    match operation::poll(client, name, &params, operation::Backoff::default(), timeout) {
+        Err(operation::PollError::Timeout(name)) => Err(format!("Gave up waiting for operation {}; resume with --operation {}", name, name)),
+        Err(operation::PollError::Failed(status)) => Err(format!("Recognition operation {} failed ({}): {}", name, status.code, status.message)),
+        Err(operation::PollError::Request(err)) => Err(format!("Failed polling operation: {:?}", err)),
+        Ok(val) => {
+            print_recognition(args, val);
+            Ok(())
+        }
+    }
+}
+
+fn recognize(args: &ArgMatches) -> Result<(), String> {
+    if let Some(name) = args.value_of("operation") {
+        println!("Resuming recognition operation {}", name);
+
+        let timeout = poll_timeout(args)?;
+
+        let mut client = recognition_client(args);
+        return poll_operation(args, &mut client, name, timeout);
+    }
+
+    if args.is_present("stream") {
+        return stream_recognize(args);
+    }
+
     if args.is_present("record") {
         println!("Recording synthesized audio");
 
         let record_path = env::temp_dir().join("record-test.wav");
 
-        record_audio(&record_path);
-        convert_audio(&record_path);
+        let sample_rate = match optional_value(args, "sample-rate")? {
+            Some(0) => return Err(String::from("Invalid value for --sample-rate: 0")),
+            sample_rate => sample_rate.unwrap_or(16_000),
+        };
+
+        let convert_path = env::temp_dir().join("record-test-converted.wav");
+
+        let poll_timeout = poll_timeout(args)?;
+
+        let preprocessing = preprocess_options(args, sample_rate)?;
+
+        let trimming = if args.is_present("trim") {
+            Some(trim_options(args)?)
+        } else {
+            None
+        };
+
+        let (device, format) = devices::select_input(args.value_of("input-device"), args.value_of("input-format"))?;
+
+        let limits = record_limits(args)?;
+
+        // A silent take is never worth paying to recognize; a clipped one is only warned about.
+        if record_audio(&record_path, &device, &format, &limits).is_silent() {
+            return Ok(());
+        }
+        let record_spec = convert_audio(&record_path, &convert_path, args.is_present("separate-channels"), sample_rate)?;
+
+        // Cheap headsets add hum, rumble and DC offset that are better removed before upload.
+        let upload_path = match preprocessing {
+            Some(options) => {
+                let preprocessed_path = env::temp_dir().join("record-test-preprocessed.wav");
+                if let Err(err) = preprocess::process(&convert_path, &preprocessed_path, &options) {
+                    return Err(format!("Failed to preprocess recording: {}", err));
+                }
+                preprocessed_path
+            },
+            None => convert_path,
+        };
+
+        // Dead air costs as much to recognize as speech does.
+        let upload_path = match trimming {
+            Some(options) => trim_audio(&upload_path, &env::temp_dir().join("record-test-trimmed.wav"), &options)?,
+            None => upload_path,
+        };
 
         let api_key = args.value_of("key").unwrap();
 
-        let mut client = RestClient::new("https://speech.googleapis.com").unwrap();
+        let mut client = recognition_client(args);
 
         // https://cloud.google.com/storage/docs/json_api/v1/how-tos/authorizing
         let params = vec![("key", api_key)];
 
-        let encoding = "LINEAR16";
-        let sample_rate_hz = 44_100.0;
-        let language = "en-US";
-        let max_alternatives = 0;
-        let profanity_filter = false;
-        let contexts: Vec<RecognitionSpeechContext> = vec![];
-        let enable_word_time_offsets = false;
+        // FLAC is lossless and roughly halves the upload compared to LINEAR16.
+        let encoding = match args.value_of("encoding").unwrap_or("flac") {
+            "linear16" => "LINEAR16",
+            _ => "FLAC",
+        };
+
+        let config = recognition_config(args, encoding, record_spec.sample_rate, record_spec.channels)?;
+
+        let payload = payload::build(&upload_path, encoding)?;
+
+        payload::validate(&config, &payload)?;
 
-        let mut audio_file = std::fs::File::open(&record_path).unwrap();
-        let mut audio_data = Vec::new();
-        audio_file.read_to_end(&mut audio_data).unwrap();
-        let audio_content: String = base64::encode(&audio_data);
+        // Synchronous recognition only accepts about a minute of audio.
+        let duration_secs = payload.duration_secs;
+        let long_running = args.is_present("async") || duration_secs > 55.0;
+
+        println!("Uploading {} bytes of {} audio", payload.content.len(), payload.encoding);
+        let audio_content: String = base64::encode(&payload.content);
 
         let data = RecognizeRequest {
-            config: RecognitionConfig {
-                encoding: String::from(encoding),
-                sample_rate_hz: sample_rate_hz,
-                language: String::from(language),
-                max_alternatives: max_alternatives,
-                profanity_filter: profanity_filter,
-                contexts: contexts,
-                enable_word_time_offsets: enable_word_time_offsets,
-            },
+            config: config,
             audio: RecognitionAudio {
                 content: audio_content,
             },
         };
 
+        if long_running {
+            println!("Recording is {:.1}s long, using long-running recognition", duration_secs);
+
+            // https://cloud.google.com/speech-to-text/docs/reference/rest/v1/speech/longrunningrecognize
+            let resp: Result<operation::Operation, Error> = client.post_capture_with(String::from("speech:longrunningrecognize"), &data, &params);
+            return match resp {
+                Err(err) => {
+                    // Print out serialized request
+                    let serialized = serde_json::to_string(&data).unwrap();
+                    file::put("request_debug.txt", &serialized).expect("Failed to get write out serialized debug data");
+
+                    Err(format!("Failed processing request: {:?}", err))
+                },
+                Ok(op) => {
+                    println!("Started recognition operation {} (resume with --operation {})", op.name, op.name);
+                    poll_operation(args, &mut client, &op.name, poll_timeout)
+                }
+            };
+        }
+
         let resp: Result<RecognizeResponse, Error> = client.post_capture_with(String::from("speech:recognize"), &data, &params);
         match resp {
             Err(err) => {
-                println!("Failed processing request: {:?}", err);
-
                 // Print out serialized request
                 let serialized = serde_json::to_string(&data).unwrap();
                 file::put("request_debug.txt", &serialized).expect("Failed to get write out serialized debug data");
+
+                return Err(format!("Failed processing request: {:?}", err));
             },
             Ok(val) => {
-                println!("Length of results is {}", val.results.len());
-
-                let mut transcript = String::new();
-                for result in val.results {
-                    if result.alternatives.len() > 0 {
-                        let alternative_info = format!(" (Confidence: {})", &result.alternatives[0].confidence);
-                        transcript += &result.alternatives[0].transcript;
-                        transcript += &alternative_info;
-                        if result.alternatives[0].words.len() > 0 {
-
-                        }
-                    }
-                }
-
-                println!("Recognition result: {}", transcript);
+                print_recognition(args, val);
             }
         }
     }
+    Ok(())
+}
+
+// Voice settings, shared by synthesis and `config show`.
+fn voice_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("pitch")
+            .long("pitch")
+            .help("Optional speaking pitch, in the range [-20.0, 20.0]. 20 means increase 20 semitones from the original pitch. -20 means decrease 20 semitones from the original pitch.")
+            .takes_value(true),
+        Arg::with_name("rate")
+            .long("rate")
+            .help("Optional speaking rate/speed, in the range [0.25, 4.0]. 1.0 is the normal native speed supported by the specific voice. 2.0 is twice as fast, and 0.5 is half as fast. If unset, defaults to the native 1.0 speed. Any other values < 0.25 or > 4.0 will return an error.")
+            .takes_value(true),
+        Arg::with_name("gain")
+            .long("gain")
+            .help("Optional volume gain (in dB) of the normal native volume supported by the specific voice, in the range [-96.0, 16.0]. If unset, or set to a value of 0.0 (dB), will play at normal native signal amplitude. A value of -6.0 (dB) will play at approximately half the amplitude of the normal native signal amplitude. A value of +6.0 (dB) will play at approximately twice the amplitude of the normal native signal amplitude. Strongly recommend not to exceed +10 (dB) as there's usually no effective increase in loudness for any value greater than that.")
+            .takes_value(true),
+        Arg::with_name("name")
+            .long("name")
+            .help("Optional voice name (i.e. en-US-Wavenet-D). If not set, the service will choose a voice based on the other parameters such as language code and voice gender.")
+            .takes_value(true),
+        Arg::with_name("language")
+            .long("language")
+            .help("Optional voice language (i.e. en-US). The language (and optionally also the region) of the voice expressed as a BCP-47 language tag, e.g. en-US. This should not include a script tag (e.g. use 'cmn-cn' rather than 'cmn-Hant-cn'), because the script will be inferred from the input provided in the synthesis input. The TTS service will use this parameter to help choose an appropriate voice. Note that the TTS service may choose a voice with a slightly different language code than the one selected; it may substitute a different region (e.g. using en-US rather than en-CA if there isn't a Canadian voice available), or even a different language, e.g. using 'nb' (Norwegian Bokmal) instead of 'no' (Norwegian)")
+            .takes_value(true),
+        Arg::with_name("gender")
+            .long("gender")
+            .help("Optional preferred voice gender (i.e. MALE, FEMALE, NEUTRAL). If not set, the service will choose a voice based on the other parameters such as language code and voice name. Note that this is only a preference, not a requirement; if a voice of the appropriate gender is not available, the synthesizer should substitute a voice with a different gender rather than failing the request.")
+            .takes_value(true),
+    ]
+}
+
+// Where voice settings come from, shared by everything that synthesizes.
+fn config_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("preset")
+            .long("preset")
+            .help("Optional named voice preset from the config file. Its settings override the config defaults and are overridden by SPEECH_TEST_* environment variables and flags.")
+            .takes_value(true),
+        Arg::with_name("config")
+            .long("config")
+            .help("Optional TOML config file with [defaults] and [presets.<name>] voice settings. If not set, $SPEECH_TEST_CONFIG or speech-test/config.toml in the user config directory is used.")
+            .takes_value(true),
+    ]
 }
 
 fn main() {
@@ -496,53 +1320,368 @@ fn main() {
                         .version("0.1.0")
                         .author("Graham Wihlidal <graham@wihlidal.ca>")
                         .about("Google Cloud text-to-speech prototype")
-                        .arg(Arg::with_name("pitch")
-                            .long("pitch")
-                            .help("Optional speaking pitch, in the range [-20.0, 20.0]. 20 means increase 20 semitones from the original pitch. -20 means decrease 20 semitones from the original pitch.")
-                            .takes_value(true))
-                        .arg(Arg::with_name("rate")
-                            .long("rate")
-                            .help("Optional speaking rate/speed, in the range [0.25, 4.0]. 1.0 is the normal native speed supported by the specific voice. 2.0 is twice as fast, and 0.5 is half as fast. If unset(0.0), defaults to the native 1.0 speed. Any other values < 0.25 or > 4.0 will return an error.")
-                            .takes_value(true))
-                        .arg(Arg::with_name("gain")
-                            .long("gain")
-                            .help("Optional volume gain (in dB) of the normal native volume supported by the specific voice, in the range [-96.0, 16.0]. If unset, or set to a value of 0.0 (dB), will play at normal native signal amplitude. A value of -6.0 (dB) will play at approximately half the amplitude of the normal native signal amplitude. A value of +6.0 (dB) will play at approximately twice the amplitude of the normal native signal amplitude. Strongly recommend not to exceed +10 (dB) as there's usually no effective increase in loudness for any value greater than that.")
-                            .takes_value(true))
+                        .setting(AppSettings::SubcommandsNegateReqs)
+                        .args(&voice_args())
+                        .args(&config_args())
+                        .subcommand(SubCommand::with_name("devices")
+                            .about("Lists audio devices and their supported stream formats")
+                            .arg(Arg::with_name("json")
+                                .long("json")
+                                .help("Print the device list as JSON")))
+                        .subcommand(SubCommand::with_name("compose")
+                            .about("Synthesizes a script of segments, each with its own voice, rate and pitch, into one WAV file joined by gaps or crossfades")
+                            .arg(Arg::with_name("key")
+                                .help("Sets cloud API key")
+                                .required(true)
+                                .index(1))
+                            .arg(Arg::with_name("script")
+                                .help("JSON script of segments, e.g. {\"segments\": [{\"text\": \"Hello\", \"voice\": \"en-US-Wavenet-C\", \"rate\": 1.1}, {\"text\": \"Hi\", \"gap\": 0.3}]}. Each segment can set voice, language, gender, rate, pitch and gain, and either a gap of silence or a crossfade in seconds before it.")
+                                .required(true)
+                                .index(2))
+                            .arg(Arg::with_name("output")
+                                .help("Where to write the composed WAV file")
+                                .required(true)
+                                .index(3))
+                            .args(&config_args()))
+                        .subcommand(SubCommand::with_name("dialogue")
+                            .about("Renders a dialogue script of \"NAME: line\" lines, each character with their own voice, into one WAV file and a JSON cue sheet")
+                            .arg(Arg::with_name("key")
+                                .help("Sets cloud API key")
+                                .required(true)
+                                .index(1))
+                            .arg(Arg::with_name("script")
+                                .help("Dialogue script. \"@NAME preset=narrator voice=en-US-Wavenet-C gender=FEMALE rate=1.1 pitch=2 gain=0 language=en-US\" lines set each character's voice before their first \"NAME: line\"; lines without a name continue the one before, and # starts a comment.")
+                                .required(true)
+                                .index(2))
+                            .arg(Arg::with_name("output")
+                                .help("Where to write the rendered WAV file")
+                                .required(true)
+                                .index(3))
+                            .arg(Arg::with_name("gap")
+                                .long("gap")
+                                .help("Optional seconds of silence between lines (default 0.3)")
+                                .takes_value(true))
+                            .arg(Arg::with_name("cues")
+                                .long("cues")
+                                .help("Optional path of the JSON cue sheet with each line's start and end time (default: the output path with a .cues.json extension)")
+                                .takes_value(true))
+                            .args(&config_args()))
+                        .subcommand(SubCommand::with_name("config")
+                            .about("Inspects voice settings from the config file, environment and flags")
+                            .setting(AppSettings::SubcommandRequiredElseHelp)
+                            .subcommand(SubCommand::with_name("show")
+                                .about("Prints the effective voice settings and where each one comes from")
+                                .args(&voice_args())
+                                .args(&config_args())))
+                        .subcommand(SubCommand::with_name("trim")
+                            .about("Trims leading and trailing silence from a WAV file")
+                            .arg(Arg::with_name("input")
+                                .help("WAV file to trim")
+                                .required(true)
+                                .index(1))
+                            .arg(Arg::with_name("output")
+                                .help("Where to write the trimmed WAV file")
+                                .required(true)
+                                .index(2))
+                            .arg(Arg::with_name("trim-threshold")
+                                .long("threshold")
+                                .help("Optional level in dBFS below which audio counts as silence (default -50)")
+                                .takes_value(true))
+                            .arg(Arg::with_name("trim-padding")
+                                .long("padding")
+                                .help("Optional seconds of silence kept either side of the audio (default 0.1)")
+                                .takes_value(true)))
                         .arg(Arg::with_name("key")
                             .help("Sets cloud API key")
                             .required(true)
                             .index(1))
                         .arg(Arg::with_name("input")
                             .help("Sets the input to synthesize (raw text or ssml)")
-                            .required(true)
+                            .required_unless("operation")
                             .index(2))
                         .arg(Arg::with_name("play")
                             .long("play")
                             .help("Enable synthesized audio playback"))
+                        .arg(Arg::with_name("target-lufs")
+                            .long("target-lufs")
+                            .help("Optional integrated loudness in LUFS (ITU-R BS.1770) to normalize synthesized audio to, e.g. -16. Unlike --gain, this gives the same loudness whatever the voice and rate.")
+                            .takes_value(true))
+                        .arg(Arg::with_name("true-peak")
+                            .long("true-peak")
+                            .help("Optional true-peak ceiling in dBTP that --target-lufs limits synthesized audio to (default -1.0)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("trim")
+                            .long("trim")
+                            .help("Trim leading and trailing silence from synthesized audio, and from recorded audio before recognition"))
+                        .arg(Arg::with_name("trim-threshold")
+                            .long("trim-threshold")
+                            .help("Optional level in dBFS below which --trim treats audio as silence (default -50)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("trim-padding")
+                            .long("trim-padding")
+                            .help("Optional seconds of silence --trim keeps either side of the audio (default 0.1)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("output-device")
+                            .long("output-device")
+                            .help("Optional playback device, by index in the playback devices of the --enumerate listing or by (part of) its name. If not set, the default output device is used.")
+                            .takes_value(true))
+                        .arg(Arg::with_name("volume")
+                            .long("volume")
+                            .help("Optional playback volume, in the range [0.0, 2.0]. 1.0 plays at the synthesized level; values above 1.0 amplify and may clip.")
+                            .takes_value(true))
                         .arg(Arg::with_name("record")
                             .long("record")
                             .help("Enable synthesized audio recording"))
+                        .arg(Arg::with_name("duration")
+                            .long("duration")
+                            .help("Optional recording length in seconds. Recording starts right away and stops on its own, so it can run unattended.")
+                            .takes_value(true))
+                        .arg(Arg::with_name("hands-free")
+                            .long("hands-free")
+                            .help("Start recording right away and record until SIGINT/SIGTERM or the maximum duration, instead of waiting for enter"))
+                        .arg(Arg::with_name("vad")
+                            .long("vad")
+                            .conflicts_with_all(&["duration", "hands-free"])
+                            .help("Start recording when speech is detected and stop after trailing silence, instead of waiting for enter"))
+                        .arg(Arg::with_name("vad-threshold")
+                            .long("vad-threshold")
+                            .help("Optional level in dBFS below which --vad treats audio as silence (default -40)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("vad-silence")
+                            .long("vad-silence")
+                            .help("Optional seconds of trailing silence that end a --vad recording (default 1.0)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("vad-pre-roll")
+                            .long("vad-pre-roll")
+                            .help("Optional seconds of audio kept from before speech is detected with --vad (default 0.3)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("max-duration")
+                            .long("max-duration")
+                            .help("Optional safety cap on recording length in seconds (default 14400, four hours)")
+                            .takes_value(true))
                         .arg(Arg::with_name("enumerate")
                             .long("enumerate")
                             .help("Enable audio device enumeration"))
-                        .arg(Arg::with_name("gender")
-                            .long("gender")
-                            .help("Optional preferred voice gender (i.e. MALE, FEMALE, NEUTRAL). If not set, the service will choose a voice based on the other parameters such as language code and voice name. Note that this is only a preference, not a requirement; if a voice of the appropriate gender is not available, the synthesizer should substitute a voice with a different gender rather than failing the request.")
+                        .arg(Arg::with_name("input-device")
+                            .long("input-device")
+                            .help("Optional microphone to record from, by index in the --enumerate listing or by (part of) its name. If not set, the default input device is used.")
+                            .takes_value(true))
+                        .arg(Arg::with_name("input-format")
+                            .long("input-format")
+                            .help("Optional input stream format as <channels>,<sample rate>,<i16|u16|f32> (i.e. 1,16000,i16), chosen from the device's supported input formats. If not set, the device's default input format is used.")
+                            .takes_value(true))
+                        .arg(Arg::with_name("async")
+                            .long("async")
+                            .help("Force long-running (asynchronous) recognition. Recordings longer than a minute always use it, since synchronous recognition rejects them."))
+                        .arg(Arg::with_name("operation")
+                            .long("operation")
+                            .help("Resume polling an existing long-running recognition operation by name instead of recording")
+                            .takes_value(true))
+                        .arg(Arg::with_name("poll-timeout")
+                            .long("poll-timeout")
+                            .help("Optional number of seconds to wait for a long-running recognition operation before giving up. The operation keeps running and can be resumed with --operation.")
                             .takes_value(true))
-                        .arg(Arg::with_name("language")
-                            .long("language")
-                            .help("Optional voice language (i.e. en-US). The language (and optionally also the region) of the voice expressed as a BCP-47 language tag, e.g. en-US. This should not include a script tag (e.g. use 'cmn-cn' rather than 'cmn-Hant-cn'), because the script will be inferred from the input provided in the synthesis input. The TTS service will use this parameter to help choose an appropriate voice. Note that the TTS service may choose a voice with a slightly different language code than the one selected; it may substitute a different region (e.g. using en-US rather than en-CA if there isn't a Canadian voice available), or even a different language, e.g. using 'nb' (Norwegian Bokmal) instead of 'no' (Norwegian)")
+                        .arg(Arg::with_name("stream")
+                            .long("stream")
+                            .help("Stream microphone audio for recognition as it is captured, printing interim and final transcripts live"))
+                        .arg(Arg::with_name("stream-endpoint")
+                            .long("stream-endpoint")
+                            .help("Address (host:port) of the streaming recognition bridge, which exchanges newline-delimited JSON streaming requests and responses")
                             .takes_value(true))
-                        .arg(Arg::with_name("name")
-                            .long("name")
-                            .help("Optional voice name (i.e. en-US-Wavenet-D). If not set, the service will choose a voice based on the other parameters such as language code and voice gender.")
+                        .arg(Arg::with_name("diarize")
+                            .long("diarize")
+                            .help("Enable speaker diarization, attributing each recognized word to a speaker"))
+                        .arg(Arg::with_name("min-speakers")
+                            .long("min-speakers")
+                            .help("Optional minimum number of speakers in the conversation when diarizing (default 2)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("max-speakers")
+                            .long("max-speakers")
+                            .help("Optional maximum number of speakers in the conversation when diarizing (default 6)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("punctuation")
+                            .long("punctuation")
+                            .help("Enable automatic punctuation in recognition transcripts"))
+                        .arg(Arg::with_name("model")
+                            .long("model")
+                            .help("Optional recognition model best suited to the audio: phone_call, video, command_and_search or default. If not set, the service chooses based on the other parameters.")
+                            .possible_values(&["phone_call", "video", "command_and_search", "default"])
+                            .takes_value(true))
+                        .arg(Arg::with_name("enhanced")
+                            .long("enhanced")
+                            .help("Use the enhanced version of the recognition model (only phone_call and video have one)"))
+                        .arg(Arg::with_name("separate-channels")
+                            .long("separate-channels")
+                            .help("Keep stereo recordings as separate channels and recognize each one on its own instead of downmixing to mono. Results are labelled by channel."))
+                        .arg(Arg::with_name("sample-rate")
+                            .long("sample-rate")
+                            .help("Optional sample rate (in Hz) recordings are resampled to before recognition (default 16000). 16 kHz captures the full bandwidth of speech; higher rates only add upload size.")
+                            .takes_value(true))
+                        .arg(Arg::with_name("encoding")
+                            .long("encoding")
+                            .help("Optional encoding of recorded audio uploaded for recognition: flac or linear16 (default flac). FLAC is lossless and about half the size.")
+                            .possible_values(&["flac", "linear16"])
+                            .takes_value(true))
+                        .arg(Arg::with_name("remove-dc")
+                            .long("remove-dc")
+                            .help("Remove any DC offset from recorded audio before recognition"))
+                        .arg(Arg::with_name("high-pass")
+                            .long("high-pass")
+                            .help("Optional high-pass cutoff in Hz applied to recorded audio before recognition, to remove rumble and hum (e.g. 80)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("noise-gate")
+                            .long("noise-gate")
+                            .help("Optional level in dBFS below which recorded audio is muted before recognition (e.g. -50)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("normalize")
+                            .long("normalize")
+                            .help("Optionally normalize recorded audio before recognition by its peak or RMS level")
+                            .possible_values(&["peak", "rms"])
+                            .takes_value(true))
+                        .arg(Arg::with_name("normalize-target")
+                            .long("normalize-target")
+                            .help("Optional --normalize target in dBFS (default -1 for peak, -20 for rms)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("output-format")
+                            .long("output-format")
+                            .help("Optional transcript output format: text, json or srt subtitles (default text)")
+                            .possible_values(&["text", "json", "srt"])
+                            .takes_value(true))
+                        .arg(Arg::with_name("speech-endpoint")
+                            .long("speech-endpoint")
+                            .help("Optional speech recognition service root (default https://speech.googleapis.com)")
                             .takes_value(true))
                         .get_matches();
 
+    if let Some(devices_matches) = matches.subcommand_matches("devices") {
+        enumerate_audio(devices_matches.is_present("json"));
+        return;
+    }
+
+    if let Some(compose_matches) = matches.subcommand_matches("compose") {
+        if let Err(err) = compose(compose_matches) {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(dialogue_matches) = matches.subcommand_matches("dialogue") {
+        if let Err(err) = dialogue(dialogue_matches) {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(config_matches) = matches.subcommand_matches("config") {
+        if let Some(show_matches) = config_matches.subcommand_matches("show") {
+            if let Err(err) = config_show(show_matches) {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(trim_matches) = matches.subcommand_matches("trim") {
+        if let Err(err) = trim_command(trim_matches) {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     if matches.is_present("enumerate") {
-        enumerate_audio();
+        enumerate_audio(false);
     }
 
-    synthesize(&matches);
-    recognize(&matches);
+    // Resuming an operation only polls it; there's nothing to synthesize.
+    if !matches.is_present("operation") {
+        if let Err(err) = synthesize(&matches) {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    }
+    if let Err(err) = recognize(&matches) {
+        println!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setting(value: f32, source: &str) -> config::Setting<f32> {
+        config::Setting { value: value, source: String::from(source) }
+    }
+
+    fn voice(rate: f32, pitch: f32, gain: f32) -> config::Voice {
+        let text = |value: &str| config::Setting { value: String::from(value), source: String::from("default") };
+        config::Voice {
+            preset: None,
+            name: text("en-US-Wavenet-D"),
+            language: text("en-US"),
+            gender: text("MALE"),
+            rate: setting(rate, "default"),
+            pitch: setting(pitch, "default"),
+            gain: setting(gain, "default"),
+        }
+    }
+
+    #[test]
+    fn range_bounds_are_inclusive() {
+        for &(range, values) in &[(PITCH_RANGE, [-20.0, 0.0, 20.0]), (RATE_RANGE, [0.25, 1.0, 4.0]), (GAIN_RANGE, [-96.0, 0.0, 16.0])] {
+            for &value in values.iter() {
+                assert_eq!(checked("Value", &setting(value, "default"), range), Ok(value));
+            }
+        }
+    }
+
+    #[test]
+    fn values_just_outside_are_rejected() {
+        let cases = [
+            (PITCH_RANGE, -20.01, "[-20.0, 20.0], not -20.01"),
+            (PITCH_RANGE, 20.01, "[-20.0, 20.0], not 20.01"),
+            (RATE_RANGE, 0.249, "[0.25, 4.0], not 0.249"),
+            (RATE_RANGE, 4.001, "[0.25, 4.0], not 4.001"),
+            (GAIN_RANGE, -96.01, "[-96.0, 16.0], not -96.01"),
+            (GAIN_RANGE, 16.01, "[-96.0, 16.0], not 16.01"),
+        ];
+        for &(range, value, message) in cases.iter() {
+            assert_eq!(checked("Value", &setting(value, "config"), range),
+                       Err(format!("Value must be in the range {} (config)", message)));
+        }
+    }
+
+    #[test]
+    fn non_finite_values_are_rejected() {
+        for &value in &[::std::f32::NAN, ::std::f32::INFINITY, ::std::f32::NEG_INFINITY] {
+            for &range in &[PITCH_RANGE, RATE_RANGE, GAIN_RANGE] {
+                assert!(checked("Value", &setting(value, "default"), range).is_err(), "{} accepted", value);
+            }
+        }
+        assert_eq!(checked("Pitch", &setting(::std::f32::NAN, "script"), PITCH_RANGE),
+                   Err(String::from("Pitch must be in the range [-20.0, 20.0], not NaN (script)")));
+    }
+
+    #[test]
+    fn audio_config_errors_name_the_setting_and_its_layer() {
+        let config = AudioConfig::new(&voice(4.0, -20.0, 16.0)).unwrap();
+        assert_eq!((config.speaking_rate, config.pitch, config.gain), (4.0, -20.0, 16.0));
+        assert_eq!(config.audio_encoding, "LINEAR16");
+
+        let mut bad = voice(1.0, 0.0, 0.0);
+        bad.pitch = setting(21.0, "environment");
+        assert_eq!(AudioConfig::new(&bad).err(), Some(String::from("Pitch must be in the range [-20.0, 20.0], not 21 (environment)")));
+
+        let mut bad = voice(1.0, 0.0, 0.0);
+        bad.rate = setting(0.1, "preset narrator");
+        assert_eq!(AudioConfig::new(&bad).err(), Some(String::from("Speaking rate must be in the range [0.25, 4.0], not 0.1 (preset narrator)")));
+
+        let mut bad = voice(1.0, 0.0, 0.0);
+        bad.gain = setting(-100.0, "command line");
+        assert_eq!(AudioConfig::new(&bad).err(), Some(String::from("Gain must be in the range [-96.0, 16.0], not -100 (command line)")));
+    }
 }
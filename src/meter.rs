@@ -0,0 +1,232 @@
+use std::io::{self, Write};
+
+// Samples this close to full scale count as clipped.
+const CLIP_LEVEL: i32 = 32766;
+
+// Range drawn by the meter bar.
+const FLOOR_DB: f32 = -60.0;
+const BAR_WIDTH: usize = 30;
+
+// A take whose loudest sample is below this is treated as silent.
+const SILENT_PEAK_DB: f32 = -50.0;
+
+// Clipping on more than this fraction of a take is worth warning about.
+const CLIPPED_FRACTION: f32 = 0.001;
+
+fn to_db(level: f64) -> f32 {
+    (20.0 * level.max(1e-5).log10()) as f32
+}
+
+#[derive(Default)]
+struct Levels {
+    samples: u64,
+    energy: f64,
+    peak: i32,
+    clipped: u64,
+}
+
+impl Levels {
+    fn add(&mut self, samples: &[i16]) {
+        for &sample in samples {
+            let magnitude = (sample as i32).abs();
+            self.energy += (sample as f64) * (sample as f64);
+            self.peak = self.peak.max(magnitude);
+            if magnitude >= CLIP_LEVEL {
+                self.clipped += 1;
+            }
+        }
+        self.samples += samples.len() as u64;
+    }
+
+    fn rms_db(&self) -> f32 {
+        if self.samples == 0 {
+            return to_db(0.0);
+        }
+        to_db((self.energy / self.samples as f64).sqrt() / 32768.0)
+    }
+
+    fn peak_db(&self) -> f32 {
+        to_db(self.peak as f64 / 32768.0)
+    }
+}
+
+/// RMS and peak levels of a whole take, for catching bad recordings before they're uploaded.
+pub struct Summary {
+    pub rms_db: f32,
+    pub peak_db: f32,
+    pub clipped: u64,
+    pub samples: u64,
+}
+
+impl Summary {
+    pub fn is_silent(&self) -> bool {
+        self.peak_db < SILENT_PEAK_DB
+    }
+
+    pub fn is_clipped(&self) -> bool {
+        self.samples > 0 && self.clipped as f32 / self.samples as f32 > CLIPPED_FRACTION
+    }
+
+    /// Prints the take's levels, with a warning if it's silent or clipped.
+    pub fn report(&self) {
+        println!("Levels: {:.1} dBFS RMS, {:.1} dBFS peak, {} clipped samples", self.rms_db, self.peak_db, self.clipped);
+        if self.is_silent() {
+            println!("Warning: the recording is silent; check the input device and its gain");
+        } else if self.is_clipped() {
+            println!("Warning: {:.2}% of the recording is clipped; lower the input gain", self.clipped as f32 * 100.0 / self.samples as f32);
+        }
+    }
+}
+
+/// A terminal level meter, redrawn in place every `interval` samples.
+pub struct LevelMeter {
+    interval: u64,
+    window: Levels,
+    take: Levels,
+}
+
+impl LevelMeter {
+    pub fn new(channels: u16, sample_rate: u32) -> LevelMeter {
+        LevelMeter {
+            // Ten redraws a second.
+            interval: (channels as u64 * sample_rate as u64 / 10).max(1),
+            window: Levels::default(),
+            take: Levels::default(),
+        }
+    }
+
+    pub fn process(&mut self, samples: &[i16]) {
+        self.process_to(samples, &mut io::stdout());
+    }
+
+    // Windows are counted across calls, so buffers of any size redraw at the same rate.
+    fn process_to<W: Write>(&mut self, samples: &[i16], out: &mut W) {
+        for chunk in samples.chunks(self.interval as usize) {
+            let room = (self.interval - self.window.samples) as usize;
+            let (now, later) = chunk.split_at(room.min(chunk.len()));
+
+            self.window.add(now);
+            self.take.add(now);
+            if self.window.samples == self.interval {
+                self.draw(out);
+                self.window = Levels::default();
+            }
+
+            self.window.add(later);
+            self.take.add(later);
+        }
+    }
+
+    fn draw<W: Write>(&self, out: &mut W) {
+        let rms_db = self.window.rms_db();
+        let filled = ((rms_db - FLOOR_DB) / -FLOOR_DB * BAR_WIDTH as f32).max(0.0).min(BAR_WIDTH as f32) as usize;
+
+        write!(out, "\r{:6.1} dBFS RMS {:6.1} dBFS peak [{}{}] clipped: {}",
+               rms_db,
+               self.window.peak_db(),
+               "#".repeat(filled),
+               " ".repeat(BAR_WIDTH - filled),
+               self.take.clipped).ok();
+        out.flush().ok();
+    }
+
+    pub fn summary(&self) -> Summary {
+        Summary {
+            rms_db: self.take.rms_db(),
+            peak_db: self.take.peak_db(),
+            clipped: self.take.clipped,
+            samples: self.take.samples,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feeds the meter in buffers of `buffer` samples and returns what each redraw showed.
+    fn redraws(meter: &mut LevelMeter, samples: &[i16], buffer: usize) -> Vec<String> {
+        let mut out = Vec::new();
+        for chunk in samples.chunks(buffer) {
+            meter.process_to(chunk, &mut out);
+        }
+        String::from_utf8(out).unwrap().split('\r').skip(1).map(String::from).collect()
+    }
+
+    fn square(amplitude: i16, count: usize) -> Vec<i16> {
+        (0..count).map(|index| if index % 2 == 0 { amplitude } else { -amplitude }).collect()
+    }
+
+    fn summary(samples: &[i16]) -> Summary {
+        let mut meter = LevelMeter::new(1, 16_000);
+        meter.process_to(samples, &mut io::sink());
+        meter.summary()
+    }
+
+    #[test]
+    fn redraws_every_tenth_of_a_second_across_buffers() {
+        // 1600 samples a window at 16 kHz mono; buffers of 333 straddle every boundary.
+        let mut samples = square(3277, 1600);
+        samples.extend(square(328, 1600));
+        samples.extend(square(32767, 1599));
+
+        let mut meter = LevelMeter::new(1, 16_000);
+        let lines = redraws(&mut meter, &samples, 333);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(" -20.0 dBFS RMS  -20.0 dBFS peak [####################          ] clipped: 0"), "{:?}", lines[0]);
+        assert!(lines[1].starts_with(" -40.0 dBFS RMS  -40.0 dBFS peak [##########                    ] clipped: 0"), "{:?}", lines[1]);
+
+        // The full-scale window completes with its last sample.
+        let lines = redraws(&mut meter, &[32767], 1);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("  -0.0 dBFS RMS   -0.0 dBFS peak ["), "{:?}", lines[0]);
+        assert!(lines[0].ends_with("] clipped: 1600"), "{:?}", lines[0]);
+    }
+
+    #[test]
+    fn buffer_size_doesnt_change_the_windows() {
+        let samples: Vec<i16> = (0..16_000).map(|index| ((index * 37) % 20_000 - 10_000) as i16).collect();
+        let expected = redraws(&mut LevelMeter::new(2, 8_000), &samples, samples.len());
+        assert_eq!(expected.len(), 10);
+        for &buffer in &[1, 7, 1599, 1600, 1601, 4000] {
+            assert_eq!(redraws(&mut LevelMeter::new(2, 8_000), &samples, buffer), expected, "buffers of {}", buffer);
+        }
+    }
+
+    #[test]
+    fn summarizes_the_whole_take() {
+        let mut samples = square(3277, 1000);
+        samples.extend(vec![0; 3000]);
+        let summary = summary(&samples);
+        assert_eq!(summary.samples, 4000);
+        assert_eq!(summary.clipped, 0);
+        assert!((summary.peak_db + 20.0).abs() < 0.01, "{}", summary.peak_db);
+        assert!((summary.rms_db + 26.02).abs() < 0.01, "{}", summary.rms_db);
+    }
+
+    #[test]
+    fn silence_threshold() {
+        // -50 dBFS is a peak of 103.6.
+        assert!(summary(&square(103, 100)).is_silent());
+        assert!(!summary(&square(104, 100)).is_silent());
+        assert!(summary(&[]).is_silent());
+        assert!(summary(&vec![0; 100]).is_silent());
+    }
+
+    #[test]
+    fn clipping_threshold() {
+        assert_eq!(summary(&[32765, -32765]).clipped, 0);
+        assert_eq!(summary(&[32766, 32767, -32766, -32767, -32768]).clipped, 5);
+
+        // More than one sample in a thousand.
+        let mut samples = vec![1000; 10_000];
+        for sample in samples.iter_mut().take(10) {
+            *sample = 32767;
+        }
+        assert!(!summary(&samples).is_clipped());
+        samples[10] = -32768;
+        assert!(summary(&samples).is_clipped());
+
+        assert!(!summary(&[]).is_clipped());
+    }
+}
@@ -0,0 +1,257 @@
+use restson::{RestClient,RestPath,Error};
+
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use RecognizeResponse;
+
+#[derive(Deserialize)]
+pub struct LongRunningRecognizeMetadata {
+    #[serde(default)]
+    #[serde(rename = "progressPercent")]
+    pub progress_percent: i32,
+}
+
+#[derive(Deserialize)]
+pub struct OperationStatus {
+    #[serde(default)]
+    #[serde(rename = "code")]
+    pub code: i32,
+
+    #[serde(default)]
+    #[serde(rename = "message")]
+    pub message: String,
+}
+
+// https://cloud.google.com/speech-to-text/docs/reference/rest/v1/operations
+#[derive(Deserialize)]
+pub struct Operation {
+    #[serde(rename = "name")]
+    pub name: String,
+
+    #[serde(default)]
+    #[serde(rename = "done")]
+    pub done: bool,
+
+    #[serde(default)]
+    #[serde(rename = "metadata")]
+    pub metadata: Option<LongRunningRecognizeMetadata>,
+
+    #[serde(default)]
+    #[serde(rename = "response")]
+    pub response: Option<RecognizeResponse>,
+
+    #[serde(default)]
+    #[serde(rename = "error")]
+    pub error: Option<OperationStatus>,
+}
+
+impl RestPath<String> for Operation {
+    fn get_path(param: String) -> Result<String, Error>
+    {
+        Ok(format!("v1/operations/{}", param))
+    }
+}
+
+pub enum PollError {
+    Request(Error),
+    Failed(OperationStatus),
+    Timeout(String),
+}
+
+/// Exponential backoff between operation polls, doubling up to `max_delay`.
+pub struct Backoff {
+    delay: Duration,
+    max_delay: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial_delay: Duration, max_delay: Duration) -> Backoff {
+        Backoff {
+            delay: initial_delay,
+            max_delay: max_delay,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = cmp::min(self.delay * 2, self.max_delay);
+        delay
+    }
+}
+
+impl Default for Backoff {
+    /// One second, doubling up to thirty.
+    fn default() -> Backoff {
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(30))
+    }
+}
+
+/// Polls `operations/{name}` until the operation is done, printing progress as it changes.
+///
+/// The operation name is all that's needed to resume, so an interrupted poll can be picked
+/// up again later with `--operation <name>`.
+pub fn poll(client: &mut RestClient, name: &str, params: &[(&str, &str)], mut backoff: Backoff, timeout: Option<Duration>) -> Result<RecognizeResponse, PollError> {
+    let started = Instant::now();
+    let mut last_progress = -1;
+
+    loop {
+        let operation: Operation = client.get_with(String::from(name), params).map_err(PollError::Request)?;
+
+        if let Some(ref metadata) = operation.metadata {
+            if metadata.progress_percent != last_progress {
+                println!("Operation {} progress: {}%", operation.name, metadata.progress_percent);
+                last_progress = metadata.progress_percent;
+            }
+        }
+
+        if operation.done {
+            if let Some(error) = operation.error {
+                return Err(PollError::Failed(error));
+            }
+
+            // A finished operation without any speech omits the response entirely.
+            return Ok(operation.response.unwrap_or(RecognizeResponse { results: vec![] }));
+        }
+
+        let delay = backoff.next_delay();
+        if let Some(timeout) = timeout {
+            if started.elapsed() + delay > timeout {
+                return Err(PollError::Timeout(operation.name));
+            }
+        }
+
+        thread::sleep(delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+
+    // A fake operations server answering each request with the next of `bodies`, repeating the
+    // last one. Each request target is reported before it's answered, so by the time a poll
+    // returns every request it made has been reported.
+    fn serve(bodies: Vec<&'static str>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            for (index, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut header = String::new();
+                    if reader.read_line(&mut header).unwrap() == 0 || header == "\r\n" {
+                        break;
+                    }
+                }
+
+                let target = request_line.split_whitespace().nth(1).unwrap_or("").to_string();
+                if sender.send(target).is_err() {
+                    break;
+                }
+
+                let body = bodies[cmp::min(index, bodies.len() - 1)];
+                write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).unwrap();
+            }
+        });
+
+        (address, receiver)
+    }
+
+    // The request targets a finished poll made, checking it made no more than `count`.
+    fn received(requests: &Receiver<String>, count: usize) -> Vec<String> {
+        let targets = (0..count).map(|_| requests.recv_timeout(Duration::from_secs(5)).expect("too few requests")).collect();
+        assert_eq!(requests.try_recv().ok(), None, "too many requests");
+        targets
+    }
+
+    const RUNNING: &str = r#"{"name": "op-1", "done": false, "metadata": {"progressPercent": 40}}"#;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+
+        let mut backoff = Backoff::default();
+        let delays: Vec<u64> = (0..7).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 30, 30]);
+    }
+
+    #[test]
+    fn polls_until_done() {
+        let (address, requests) = serve(vec![
+            RUNNING,
+            r#"{"name": "op-1", "done": false, "metadata": {"progressPercent": 80}}"#,
+            r#"{"name": "op-1", "done": true, "response": {"results": [{"alternatives": [{"transcript": "hello world", "confidence": 0.9}]}]}}"#,
+        ]);
+        let mut client = RestClient::new(&address).unwrap();
+
+        let started = Instant::now();
+        let backoff = Backoff::new(Duration::from_millis(50), Duration::from_secs(1));
+        let response = match poll(&mut client, "op-1", &[("key", "secret")], backoff, None) {
+            Ok(response) => response,
+            Err(_) => panic!("polling failed"),
+        };
+
+        // Two waits, the second twice the first.
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert_eq!(received(&requests, 3), vec!["/v1/operations/op-1?key=secret"; 3]);
+
+        assert_eq!(response.results.len(), 1);
+        assert_eq!(response.results[0].alternatives[0].transcript, "hello world");
+    }
+
+    #[test]
+    fn done_without_a_response_has_no_results() {
+        let (address, _requests) = serve(vec![r#"{"name": "op-1", "done": true}"#]);
+        let mut client = RestClient::new(&address).unwrap();
+
+        match poll(&mut client, "op-1", &[], Backoff::default(), None) {
+            Ok(response) => assert!(response.results.is_empty()),
+            Err(_) => panic!("polling failed"),
+        }
+    }
+
+    #[test]
+    fn reports_a_failed_operation() {
+        let (address, _requests) = serve(vec![r#"{"name": "op-1", "done": true, "error": {"code": 3, "message": "bad audio"}}"#]);
+        let mut client = RestClient::new(&address).unwrap();
+
+        match poll(&mut client, "op-1", &[], Backoff::default(), None) {
+            Err(PollError::Failed(status)) => {
+                assert_eq!(status.code, 3);
+                assert_eq!(status.message, "bad audio");
+            },
+            _ => panic!("expected the operation's error"),
+        }
+    }
+
+    #[test]
+    fn gives_up_after_the_timeout() {
+        let (address, requests) = serve(vec![RUNNING]);
+        let mut client = RestClient::new(&address).unwrap();
+
+        let started = Instant::now();
+        let backoff = Backoff::new(Duration::from_millis(500), Duration::from_millis(500));
+        match poll(&mut client, "op-1", &[], backoff, Some(Duration::from_millis(1250))) {
+            Err(PollError::Timeout(name)) => assert_eq!(name, "op-1"),
+            _ => panic!("expected a timeout"),
+        }
+
+        // Polls at 0, 500 and 1000 ms; a fourth would come after the timeout, so it stops
+        // instead of waiting for it. The requests have 250 ms between them to spare.
+        assert!(started.elapsed() >= Duration::from_millis(1000));
+        assert_eq!(received(&requests, 3).len(), 3);
+    }
+}
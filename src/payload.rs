@@ -0,0 +1,203 @@
+use hound;
+
+use std::path::Path;
+
+use flac;
+use RecognitionConfig;
+
+/// Audio content for `RecognitionAudio`, along with the format its data is actually in.
+pub struct AudioPayload {
+    pub encoding: &'static str,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub duration_secs: f32,
+    pub content: Vec<u8>,
+}
+
+// https://cloud.google.com/speech-to-text/docs/reference/rest/v1/RecognitionConfig#AudioEncoding
+const MIN_SAMPLE_RATE: u32 = 8_000;
+const MAX_SAMPLE_RATE: u32 = 48_000;
+
+/// Builds the upload for a WAV file in the given encoding.
+///
+/// LINEAR16 content is the bare little-endian PCM samples; the RIFF header is stripped rather
+/// than left for the service to tolerate. FLAC content is a complete FLAC stream.
+pub fn build<P: AsRef<Path>>(path: P, encoding: &str) -> Result<AudioPayload, String> {
+    let reader = hound::WavReader::open(&path).map_err(|err| format!("Failed to read {:?}: {}", path.as_ref(), err))?;
+    let spec = reader.spec();
+
+    if spec.sample_format != hound::SampleFormat::Int || spec.bits_per_sample != 16 {
+        return Err(format!("Recognition audio must be 16-bit integer PCM, not {}-bit {:?}", spec.bits_per_sample, spec.sample_format));
+    }
+    if spec.sample_rate < MIN_SAMPLE_RATE || spec.sample_rate > MAX_SAMPLE_RATE {
+        return Err(format!("Recognition audio sample rate must be between {} and {} Hz, not {} Hz", MIN_SAMPLE_RATE, MAX_SAMPLE_RATE, spec.sample_rate));
+    }
+
+    let duration_secs = reader.duration() as f32 / spec.sample_rate as f32;
+
+    let (encoding, content) = match encoding {
+        "LINEAR16" => {
+            let mut content = Vec::with_capacity(reader.len() as usize * 2);
+            for sample in reader.into_samples::<i16>() {
+                let sample = sample.map_err(|err| format!("Failed to read {:?}: {}", path.as_ref(), err))?;
+                content.push(sample as u8);
+                content.push((sample >> 8) as u8);
+            }
+            ("LINEAR16", content)
+        },
+        "FLAC" => {
+            ("FLAC", flac::encode_wav(&path)?)
+        },
+        _ => return Err(format!("Unsupported recognition encoding {}", encoding)),
+    };
+
+    Ok(AudioPayload {
+        encoding: encoding,
+        sample_rate: spec.sample_rate,
+        channels: spec.channels,
+        duration_secs: duration_secs,
+        content: content,
+    })
+}
+
+/// Checks that the config describes the payload's data, so mismatches fail here rather than
+/// coming back from the service as garbled transcripts or opaque errors.
+pub fn validate(config: &RecognitionConfig, payload: &AudioPayload) -> Result<(), String> {
+    if config.encoding != payload.encoding {
+        return Err(format!("Config encoding {} doesn't match {} audio payload", config.encoding, payload.encoding));
+    }
+    if config.sample_rate_hz as u32 != payload.sample_rate {
+        return Err(format!("Config sample rate {} Hz doesn't match {} Hz audio data", config.sample_rate_hz, payload.sample_rate));
+    }
+    if config.audio_channel_count as u16 != payload.channels {
+        return Err(format!("Config channel count {} doesn't match {}-channel audio data", config.audio_channel_count, payload.channels));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    use std::fs;
+
+    fn wav(spec: hound::WavSpec, frames: usize) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut writer = hound::WavWriter::create(file.path(), spec).unwrap();
+        for index in 0..frames * spec.channels as usize {
+            match spec.sample_format {
+                hound::SampleFormat::Float => writer.write_sample(0.25f32).unwrap(),
+                hound::SampleFormat::Int => writer.write_sample((index as i32 * 257 - 30_000) % (1 << (spec.bits_per_sample - 1))).unwrap(),
+            }
+        }
+        writer.finalize().unwrap();
+        file
+    }
+
+    fn pcm(channels: u16, sample_rate: u32, bits_per_sample: u16) -> hound::WavSpec {
+        hound::WavSpec {
+            channels: channels,
+            sample_rate: sample_rate,
+            bits_per_sample: bits_per_sample,
+            sample_format: hound::SampleFormat::Int,
+        }
+    }
+
+    fn build_err(file: &tempfile::NamedTempFile, encoding: &str) -> String {
+        match build(file.path(), encoding) {
+            Ok(_) => panic!("{} payload should be rejected", encoding),
+            Err(err) => err,
+        }
+    }
+
+    // The bytes of the file's data chunk.
+    fn data_chunk(bytes: &[u8]) -> &[u8] {
+        let mut position = 12;
+        while position + 8 <= bytes.len() {
+            let size = bytes[position + 4] as usize
+                | (bytes[position + 5] as usize) << 8
+                | (bytes[position + 6] as usize) << 16
+                | (bytes[position + 7] as usize) << 24;
+            if &bytes[position..position + 4] == b"data" {
+                return &bytes[position + 8..position + 8 + size];
+            }
+            position += 8 + size + size % 2;
+        }
+        panic!("no data chunk");
+    }
+
+    #[test]
+    fn linear16_is_the_bare_data_chunk() {
+        let file = wav(pcm(2, 16_000, 16), 8_000);
+        let payload = build(file.path(), "LINEAR16").unwrap();
+
+        let bytes = fs::read(file.path()).unwrap();
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(payload.content.len(), 8_000 * 2 * 2);
+        assert_eq!(&payload.content[..], data_chunk(&bytes));
+
+        assert_eq!(payload.encoding, "LINEAR16");
+        assert_eq!(payload.sample_rate, 16_000);
+        assert_eq!(payload.channels, 2);
+        assert_eq!(payload.duration_secs, 0.5);
+    }
+
+    #[test]
+    fn flac_is_a_flac_stream() {
+        let file = wav(pcm(1, 8_000, 16), 1_000);
+        let payload = build(file.path(), "FLAC").unwrap();
+        assert_eq!(payload.encoding, "FLAC");
+        assert_eq!(&payload.content[..4], b"fLaC");
+        assert_eq!(payload.duration_secs, 0.125);
+    }
+
+    #[test]
+    fn rejects_audio_the_service_cant_take() {
+        assert_eq!(build_err(&wav(pcm(1, 16_000, 24), 10), "LINEAR16"), "Recognition audio must be 16-bit integer PCM, not 24-bit Int");
+        assert_eq!(build_err(&wav(pcm(1, 16_000, 8), 10), "LINEAR16"), "Recognition audio must be 16-bit integer PCM, not 8-bit Int");
+        let float = hound::WavSpec { sample_format: hound::SampleFormat::Float, ..pcm(1, 16_000, 32) };
+        assert_eq!(build_err(&wav(float, 10), "FLAC"), "Recognition audio must be 16-bit integer PCM, not 32-bit Float");
+
+        assert_eq!(build_err(&wav(pcm(1, 7_999, 16), 10), "LINEAR16"), "Recognition audio sample rate must be between 8000 and 48000 Hz, not 7999 Hz");
+        assert_eq!(build_err(&wav(pcm(1, 48_001, 16), 10), "LINEAR16"), "Recognition audio sample rate must be between 8000 and 48000 Hz, not 48001 Hz");
+        assert!(build(wav(pcm(1, 8_000, 16), 10).path(), "LINEAR16").is_ok());
+        assert!(build(wav(pcm(1, 48_000, 16), 10).path(), "LINEAR16").is_ok());
+
+        assert_eq!(build_err(&wav(pcm(1, 16_000, 16), 10), "MP3"), "Unsupported recognition encoding MP3");
+    }
+
+    fn config(encoding: &str, sample_rate_hz: f32, audio_channel_count: i32) -> RecognitionConfig {
+        RecognitionConfig {
+            encoding: String::from(encoding),
+            sample_rate_hz: sample_rate_hz,
+            language: String::from("en-US"),
+            max_alternatives: 1,
+            profanity_filter: false,
+            contexts: Vec::new(),
+            enable_word_time_offsets: false,
+            diarization_config: None,
+            enable_automatic_punctuation: false,
+            model: None,
+            use_enhanced: false,
+            audio_channel_count: audio_channel_count,
+            enable_separate_recognition_per_channel: false,
+        }
+    }
+
+    #[test]
+    fn validate_catches_config_mismatches() {
+        let payload = AudioPayload {
+            encoding: "LINEAR16",
+            sample_rate: 16_000,
+            channels: 2,
+            duration_secs: 1.0,
+            content: Vec::new(),
+        };
+
+        assert_eq!(validate(&config("LINEAR16", 16_000.0, 2), &payload), Ok(()));
+        assert_eq!(validate(&config("FLAC", 16_000.0, 2), &payload), Err(String::from("Config encoding FLAC doesn't match LINEAR16 audio payload")));
+        assert_eq!(validate(&config("LINEAR16", 44_100.0, 2), &payload), Err(String::from("Config sample rate 44100 Hz doesn't match 16000 Hz audio data")));
+        assert_eq!(validate(&config("LINEAR16", 16_000.0, 1), &payload), Err(String::from("Config channel count 1 doesn't match 2-channel audio data")));
+    }
+}
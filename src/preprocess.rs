@@ -0,0 +1,422 @@
+use hound;
+
+use std::f64::consts::PI;
+use std::path::Path;
+
+use convert::{self, ConvertError, SampleReader};
+
+// Frames processed per block; the chain keeps its state between blocks.
+const BLOCK_FRAMES: usize = 4096;
+
+// Cutoff of the DC blocker, well below anything audible.
+const DC_CUTOFF_HZ: f64 = 10.0;
+
+// How quickly the noise gate opens and closes, and how long it stays open after the level
+// drops, so word endings aren't chopped off.
+const GATE_ATTACK_SECS: f64 = 0.002;
+const GATE_RELEASE_SECS: f64 = 0.08;
+const GATE_HOLD_SECS: f64 = 0.15;
+
+// Loudness normalization never pushes peaks above this.
+const PEAK_CEILING_DB: f32 = -1.0;
+
+pub enum Normalize {
+    /// Scale so the loudest sample sits at the given dBFS.
+    Peak(f32),
+    /// Scale so the RMS level sits at the given dBFS, as far as `PEAK_CEILING_DB` allows.
+    Rms(f32),
+}
+
+/// Which stages run, in this order: DC removal, high-pass, noise gate, normalization.
+pub struct Options {
+    pub remove_dc: bool,
+    pub high_pass_hz: Option<f32>,
+    pub gate_threshold_db: Option<f32>,
+    pub normalize: Option<Normalize>,
+}
+
+fn db_to_gain(db: f32) -> f64 {
+    10f64.powf(db as f64 / 20.0)
+}
+
+fn time_constant(secs: f64, sample_rate: f64) -> f64 {
+    (-1.0 / (secs * sample_rate)).exp()
+}
+
+struct DcBlocker {
+    pole: f64,
+    last_input: f64,
+    last_output: f64,
+}
+
+impl DcBlocker {
+    fn new(sample_rate: f64) -> DcBlocker {
+        DcBlocker {
+            pole: (-2.0 * PI * DC_CUTOFF_HZ / sample_rate).exp(),
+            last_input: 0.0,
+            last_output: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let output = input - self.last_input + self.pole * self.last_output;
+        self.last_input = input;
+        self.last_output = output;
+        output
+    }
+}
+
+/// Second-order Butterworth high-pass, from the RBJ audio EQ cookbook.
+struct HighPass {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    z1: f64,
+    z2: f64,
+}
+
+impl HighPass {
+    fn new(cutoff_hz: f64, sample_rate: f64) -> HighPass {
+        let w0 = 2.0 * PI * cutoff_hz / sample_rate;
+        let alpha = w0.sin() / 2f64.sqrt();
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+
+        HighPass {
+            b0: (1.0 + cos) / 2.0 / a0,
+            b1: -(1.0 + cos) / a0,
+            b2: (1.0 + cos) / 2.0 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        // Transposed direct form II.
+        let output = self.b0 * input + self.z1;
+        self.z1 = self.b1 * input - self.a1 * output + self.z2;
+        self.z2 = self.b2 * input - self.a2 * output;
+        output
+    }
+}
+
+/// Mutes the signal while its level stays under a threshold, with all channels gated together.
+struct NoiseGate {
+    threshold: f64,
+    envelope_release: f64,
+    attack: f64,
+    release: f64,
+    hold_frames: usize,
+
+    envelope: f64,
+    held_frames: usize,
+    gain: f64,
+}
+
+impl NoiseGate {
+    fn new(threshold_db: f32, sample_rate: f64) -> NoiseGate {
+        let hold_frames = (GATE_HOLD_SECS * sample_rate) as usize;
+        NoiseGate {
+            threshold: db_to_gain(threshold_db),
+            envelope_release: time_constant(GATE_RELEASE_SECS, sample_rate),
+            attack: time_constant(GATE_ATTACK_SECS, sample_rate),
+            release: time_constant(GATE_RELEASE_SECS, sample_rate),
+            hold_frames: hold_frames,
+            envelope: 0.0,
+            // Start closed rather than holding open over the first frames of a quiet take.
+            held_frames: hold_frames + 1,
+            gain: 0.0,
+        }
+    }
+
+    fn process(&mut self, frame: &mut [f64]) {
+        let level = frame.iter().fold(0.0f64, |level, sample| level.max(sample.abs()));
+        self.envelope = level.max(self.envelope * self.envelope_release);
+
+        if self.envelope >= self.threshold {
+            self.held_frames = 0;
+        } else {
+            self.held_frames += 1;
+        }
+
+        let (target, coefficient) = if self.held_frames <= self.hold_frames {
+            (1.0, self.attack)
+        } else {
+            (0.0, self.release)
+        };
+        self.gain = target + (self.gain - target) * coefficient;
+
+        for sample in frame.iter_mut() {
+            *sample *= self.gain;
+        }
+    }
+}
+
+/// The filtering stages, with their state carried from block to block.
+struct Chain {
+    channels: usize,
+    dc: Vec<DcBlocker>,
+    high_pass: Vec<HighPass>,
+    gate: Option<NoiseGate>,
+    frame: Vec<f64>,
+}
+
+impl Chain {
+    fn new(options: &Options, spec: &hound::WavSpec) -> Chain {
+        let channels = spec.channels as usize;
+        let sample_rate = spec.sample_rate as f64;
+
+        let dc = if options.remove_dc {
+            (0..channels).map(|_| DcBlocker::new(sample_rate)).collect()
+        } else {
+            Vec::new()
+        };
+        let high_pass = match options.high_pass_hz {
+            Some(cutoff_hz) => (0..channels).map(|_| HighPass::new(cutoff_hz as f64, sample_rate)).collect(),
+            None => Vec::new(),
+        };
+
+        Chain {
+            channels: channels,
+            dc: dc,
+            high_pass: high_pass,
+            gate: options.gate_threshold_db.map(|threshold_db| NoiseGate::new(threshold_db, sample_rate)),
+            frame: vec![0.0; channels],
+        }
+    }
+
+    fn process(&mut self, block: &mut [f32]) {
+        for samples in block.chunks_mut(self.channels) {
+            for (channel, &sample) in samples.iter().enumerate() {
+                self.frame[channel] = sample as f64;
+            }
+
+            for (sample, dc) in self.frame.iter_mut().zip(self.dc.iter_mut()) {
+                *sample = dc.process(*sample);
+            }
+            for (sample, high_pass) in self.frame.iter_mut().zip(self.high_pass.iter_mut()) {
+                *sample = high_pass.process(*sample);
+            }
+            if let Some(ref mut gate) = self.gate {
+                gate.process(&mut self.frame);
+            }
+
+            for (sample, &filtered) in samples.iter_mut().zip(self.frame.iter()) {
+                *sample = filtered as f32;
+            }
+        }
+    }
+}
+
+/// Gain that normalization would apply to the filtered signal.
+fn normalization_gain<P: AsRef<Path>>(input: P, options: &Options, normalize: &Normalize) -> Result<f64, ConvertError> {
+    let mut reader = SampleReader::open(input)?;
+    let mut chain = Chain::new(options, &reader.spec());
+
+    let mut peak = 0.0f64;
+    let mut energy = 0.0f64;
+    let mut samples = 0u64;
+
+    let mut block = Vec::new();
+    while reader.read_block(&mut block, BLOCK_FRAMES)? > 0 {
+        chain.process(&mut block);
+        for &sample in block.iter() {
+            peak = peak.max((sample as f64).abs());
+            energy += (sample as f64) * (sample as f64);
+        }
+        samples += block.len() as u64;
+    }
+
+    // There's nothing to scale up in silence.
+    if peak == 0.0 {
+        return Ok(1.0);
+    }
+
+    Ok(match *normalize {
+        Normalize::Peak(target_db) => db_to_gain(target_db) / peak,
+        Normalize::Rms(target_db) => {
+            let rms = (energy / samples as f64).sqrt();
+            (db_to_gain(target_db) / rms).min(db_to_gain(PEAK_CEILING_DB) / peak)
+        },
+    })
+}
+
+/// Runs the enabled stages over `input`, writing the result to `output` in the same format.
+///
+/// Normalization needs the level of the whole filtered take, so when it's enabled the input is
+/// filtered twice: once to measure it, and again to write it out at the right gain.
+pub fn process<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q, options: &Options) -> Result<(), ConvertError> {
+    let gain = match options.normalize {
+        Some(ref normalize) => normalization_gain(&input, options, normalize)?,
+        None => 1.0,
+    } as f32;
+
+    let mut reader = SampleReader::open(&input)?;
+    let spec = reader.spec();
+    convert::check_output_spec(&spec)?;
+
+    let mut chain = Chain::new(options, &spec);
+    let mut writer = hound::WavWriter::create(&output, spec)?;
+
+    let mut block = Vec::with_capacity(BLOCK_FRAMES * spec.channels as usize);
+    while reader.read_block(&mut block, BLOCK_FRAMES)? > 0 {
+        chain.process(&mut block);
+        for sample in block.iter_mut() {
+            *sample *= gain;
+        }
+        convert::write_block(&mut writer, &block)?;
+    }
+
+    writer.finalize()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+    use test_signal;
+
+    const RATE: f64 = 16_000.0;
+
+    fn rms(samples: &[f64]) -> f64 {
+        (samples.iter().map(|sample| sample * sample).sum::<f64>() / samples.len() as f64).sqrt()
+    }
+
+    fn peak(samples: &[f64]) -> f64 {
+        samples.iter().fold(0.0f64, |peak, sample| peak.max(sample.abs()))
+    }
+
+    fn gain_db(input: &[f64], output: &[f64]) -> f64 {
+        20.0 * (rms(output) / rms(input)).log10()
+    }
+
+    #[test]
+    fn dc_blocker_removes_an_offset() {
+        let input: Vec<f64> = test_signal::sine(440.0, 0.25, 0.0, RATE as u32, RATE as usize * 2).iter().map(|sample| sample + 0.5).collect();
+        let mut dc = DcBlocker::new(RATE);
+        let output: Vec<f64> = input.iter().map(|&sample| dc.process(sample)).collect();
+
+        let settled = &output[RATE as usize..];
+        let mean = settled.iter().sum::<f64>() / settled.len() as f64;
+        assert!(mean.abs() < 1e-3, "mean {}", mean);
+        assert!((peak(settled) - 0.25).abs() < 0.01, "peak {}", peak(settled));
+    }
+
+    #[test]
+    fn high_pass_attenuates_rumble_and_passes_speech() {
+        let frames = RATE as usize;
+        let response = |hz: f64| {
+            let input = test_signal::sine(hz, 0.5, 0.0, RATE as u32, frames);
+            let mut high_pass = HighPass::new(80.0, RATE);
+            let output: Vec<f64> = input.iter().map(|&sample| high_pass.process(sample)).collect();
+            gain_db(&input[frames / 2..], &output[frames / 2..])
+        };
+
+        // Two poles: 12 dB per octave, so two octaves below the cutoff is 24 dB down.
+        let rumble = response(20.0);
+        assert!(rumble < -23.0, "20 Hz at {} dB", rumble);
+        let speech = response(1000.0);
+        assert!(speech.abs() < 0.1, "1 kHz at {} dB", speech);
+    }
+
+    #[test]
+    fn noise_gate_opens_holds_and_closes() {
+        let mut gate = NoiseGate::new(-40.0, RATE);
+        let mut run = |samples: &[f64]| -> Vec<f64> {
+            samples.iter().map(|&sample| {
+                let mut frame = [sample, sample * 0.5];
+                gate.process(&mut frame);
+                assert_eq!(frame[1], frame[0] * 0.5, "channels should be gated together");
+                frame[0]
+            }).collect()
+        };
+        let secs = |secs: f64| (secs * RATE) as usize;
+
+        // Noise under the threshold never opens it.
+        let hiss = test_signal::sine(3000.0, 0.001, 0.0, RATE as u32, secs(0.5));
+        assert!(peak(&run(&hiss)) < 1e-6);
+
+        // Speech over it opens within a few milliseconds.
+        let speech = test_signal::sine(440.0, 0.1, 0.0, RATE as u32, secs(0.5));
+        let output = run(&speech);
+        assert!(gain_db(&speech[secs(0.01)..], &output[secs(0.01)..]).abs() < 0.01);
+
+        // It stays open through a short pause, then closes.
+        let pause = test_signal::sine(3000.0, 0.001, 0.0, RATE as u32, secs(1.5));
+        let output = run(&pause);
+        assert!(gain_db(&pause[..secs(0.1)], &output[..secs(0.1)]).abs() < 0.01);
+        assert!(peak(&output[secs(1.2)..]) < 1e-6);
+    }
+
+    fn process_float(input: &[f64], options: &Options) -> Vec<f64> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: RATE as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let input_file = tempfile::NamedTempFile::new().unwrap();
+        let output_file = tempfile::NamedTempFile::new().unwrap();
+
+        let mut writer = hound::WavWriter::create(input_file.path(), spec).unwrap();
+        for &sample in input {
+            writer.write_sample(sample as f32).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        if let Err(err) = process(input_file.path(), output_file.path(), options) {
+            panic!("processing failed: {}", err);
+        }
+        let mut reader = hound::WavReader::open(output_file.path()).unwrap();
+        assert_eq!(reader.spec(), spec);
+        reader.samples::<f32>().map(|sample| sample.unwrap() as f64).collect()
+    }
+
+    fn normalize_only(normalize: Normalize) -> Options {
+        Options {
+            remove_dc: false,
+            high_pass_hz: None,
+            gate_threshold_db: None,
+            normalize: Some(normalize),
+        }
+    }
+
+    fn db(gain: f64) -> f64 {
+        20.0 * gain.log10()
+    }
+
+    #[test]
+    fn peak_normalization_hits_the_target() {
+        // Longer than a block, so the gain is measured over the whole take.
+        let input = test_signal::sine(440.0, 0.05, 0.0, RATE as u32, BLOCK_FRAMES * 3);
+        let output = process_float(&input, &normalize_only(Normalize::Peak(-3.0)));
+        assert_eq!(output.len(), input.len());
+        assert!((db(peak(&output)) + 3.0).abs() < 0.01, "peak at {} dBFS", db(peak(&output)));
+    }
+
+    #[test]
+    fn rms_normalization_hits_the_target() {
+        let input = test_signal::sine(440.0, 0.05, 0.0, RATE as u32, BLOCK_FRAMES * 3);
+        let output = process_float(&input, &normalize_only(Normalize::Rms(-20.0)));
+        assert!((db(rms(&output)) + 20.0).abs() < 0.01, "RMS at {} dBFS", db(rms(&output)));
+    }
+
+    #[test]
+    fn rms_normalization_stops_at_the_peak_ceiling() {
+        // A sine at -3 dBFS RMS would peak at 0 dBFS.
+        let input = test_signal::sine(440.0, 0.05, 0.0, RATE as u32, BLOCK_FRAMES * 3);
+        let output = process_float(&input, &normalize_only(Normalize::Rms(-3.0)));
+        assert!((db(peak(&output)) - PEAK_CEILING_DB as f64).abs() < 0.01, "peak at {} dBFS", db(peak(&output)));
+    }
+
+    #[test]
+    fn silence_is_left_alone() {
+        let output = process_float(&vec![0.0; 1000], &normalize_only(Normalize::Peak(-1.0)));
+        assert_eq!(output, vec![0.0; 1000]);
+    }
+}
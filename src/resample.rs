@@ -0,0 +1,252 @@
+use std::f64::consts::PI;
+
+// Zero crossings of the sinc kept on each side of the kernel centre.
+const ZERO_CROSSINGS: f64 = 24.0;
+
+// Fraction of the lower Nyquist frequency kept in the passband, leaving room for the
+// Blackman window's transition band to fall off before aliases fold back in.
+const ROLLOFF: f64 = 0.88;
+
+// Kernel table entries per input sample, linearly interpolated in between.
+const TABLE_RESOLUTION: usize = 512;
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+fn blackman(x: f64) -> f64 {
+    // x in [-1, 1] across the whole window.
+    let phase = PI * (x + 1.0);
+    0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos()
+}
+
+/// Band-limited sample rate conversion with a Blackman-windowed sinc kernel.
+///
+/// Works on interleaved frames in chunks of any size, so it can sit in a streaming chain;
+/// call `flush` once the input is exhausted to drain the kernel's tail.
+pub struct Resampler {
+    channels: usize,
+    from_rate: u64,
+    to_rate: u64,
+
+    // Kernel taps on each side of the output position, in input samples.
+    half_width: usize,
+    table: Vec<f32>,
+
+    // Interleaved input frames starting at absolute frame `buffer_start`, which begins negative
+    // so that the first outputs see silence before the start of the signal.
+    buffer: Vec<f32>,
+    buffer_start: i64,
+    input_frames: u64,
+    output_index: u64,
+
+    weights: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(channels: u16, from_rate: u32, to_rate: u32) -> Resampler {
+        let cutoff = ROLLOFF * (to_rate as f64 / from_rate as f64).min(1.0);
+        let reach = ZERO_CROSSINGS / cutoff;
+        let half_width = reach.ceil() as usize;
+
+        let table = (0..half_width * TABLE_RESOLUTION + 2)
+            .map(|index| {
+                let t = index as f64 / TABLE_RESOLUTION as f64;
+                if t >= reach {
+                    0.0
+                } else {
+                    (cutoff * sinc(cutoff * t) * blackman(t / reach)) as f32
+                }
+            })
+            .collect();
+
+        Resampler {
+            channels: channels as usize,
+            from_rate: from_rate as u64,
+            to_rate: to_rate as u64,
+            half_width: half_width,
+            table: table,
+            buffer: vec![0.0; half_width * channels as usize],
+            buffer_start: -(half_width as i64),
+            input_frames: 0,
+            output_index: 0,
+            weights: vec![0.0; half_width * 2],
+        }
+    }
+
+    fn kernel(&self, t: f64) -> f32 {
+        let position = t.abs() * TABLE_RESOLUTION as f64;
+        let index = position as usize;
+        if index + 1 >= self.table.len() {
+            return 0.0;
+        }
+        let frac = (position - index as f64) as f32;
+        self.table[index] + (self.table[index + 1] - self.table[index]) * frac
+    }
+
+    // Input position of output `index` as a whole frame and a fraction, computed exactly so
+    // that rounding never drifts over long recordings.
+    fn position(&self, index: u64) -> (i64, f64) {
+        let numerator = index * self.from_rate;
+        ((numerator / self.to_rate) as i64, (numerator % self.to_rate) as f64 / self.to_rate as f64)
+    }
+
+    fn generate(&mut self, limit: Option<u64>, output: &mut Vec<f32>) {
+        let half_width = self.half_width as i64;
+        let buffer_end = self.buffer_start + (self.buffer.len() / self.channels) as i64;
+
+        loop {
+            let (center, frac) = self.position(self.output_index);
+            if center + half_width >= buffer_end {
+                break;
+            }
+            if let Some(limit) = limit {
+                if center as u64 >= limit {
+                    break;
+                }
+            }
+
+            let first = center - half_width + 1;
+            for tap in 0..self.weights.len() {
+                self.weights[tap] = self.kernel((first + tap as i64 - center) as f64 - frac);
+            }
+
+            let offset = (first - self.buffer_start) as usize * self.channels;
+            for channel in 0..self.channels {
+                let mut sum = 0.0;
+                for (tap, weight) in self.weights.iter().enumerate() {
+                    sum += self.buffer[offset + tap * self.channels + channel] * weight;
+                }
+                output.push(sum);
+            }
+
+            self.output_index += 1;
+        }
+
+        // Drop the frames no later output can reach.
+        let (center, _) = self.position(self.output_index);
+        let keep_from = center - half_width + 1;
+        if keep_from > self.buffer_start {
+            let drop = (keep_from - self.buffer_start) as usize * self.channels;
+            let drop = drop.min(self.buffer.len());
+            self.buffer.drain(..drop);
+            self.buffer_start += (drop / self.channels) as i64;
+        }
+    }
+
+    /// Resamples interleaved `input`, appending whatever output is ready to `output`.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        if self.from_rate == self.to_rate {
+            output.extend_from_slice(input);
+            return;
+        }
+
+        self.buffer.extend_from_slice(input);
+        self.input_frames += (input.len() / self.channels) as u64;
+        self.generate(None, output);
+    }
+
+    /// Drains the remaining output once all input has been processed.
+    pub fn flush(&mut self, output: &mut Vec<f32>) {
+        if self.from_rate == self.to_rate {
+            return;
+        }
+
+        let padding = (self.half_width + 1) * self.channels;
+        self.buffer.extend(::std::iter::repeat(0.0).take(padding));
+        let limit = self.input_frames;
+        self.generate(Some(limit), output);
+    }
+}
+
+/// Resamples a complete interleaved signal in one go.
+pub fn resample(samples: &[f32], channels: u16, from_rate: u32, to_rate: u32) -> Vec<f32> {
+    let mut resampler = Resampler::new(channels, from_rate, to_rate);
+    let mut output = Vec::with_capacity((samples.len() as u64 * to_rate as u64 / from_rate as u64) as usize + channels as usize);
+    resampler.process(samples, &mut output);
+    resampler.flush(&mut output);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_signal::{self, Noise};
+
+    // Gain in dB of a half second sine at `frequency`, measured away from the edges.
+    fn gain_db(from_rate: u32, to_rate: u32, frequency: f64) -> f64 {
+        let amplitude = 0.5;
+        let input: Vec<f32> = test_signal::sine(frequency, amplitude, 0.0, from_rate, from_rate as usize / 2)
+            .iter()
+            .map(|&sample| sample as f32)
+            .collect();
+        let output = resample(&input, 1, from_rate, to_rate);
+
+        let steady = &output[output.len() / 4..output.len() * 3 / 4];
+        let rms = (steady.iter().map(|&sample| sample as f64 * sample as f64).sum::<f64>() / steady.len() as f64).sqrt();
+        20.0 * (rms * 2f64.sqrt() / amplitude).log10()
+    }
+
+    fn check_response(from_rate: u32) {
+        // Flat to within 0.1 dB across the speech band...
+        for frequency in (1..13).map(|step| step as f64 * 500.0) {
+            let gain = gain_db(from_rate, 16_000, frequency);
+            assert!(gain.abs() < 0.1, "{} Hz passes at {:.3} dB", frequency, gain);
+        }
+
+        // ...with nothing above the new Nyquist frequency folding back in.
+        for frequency in (8..21).map(|step| step as f64 * 1000.0) {
+            let gain = gain_db(from_rate, 16_000, frequency);
+            assert!(gain < -70.0, "{} Hz leaks through at {:.1} dB", frequency, gain);
+        }
+    }
+
+    #[test]
+    fn response_48k_to_16k() {
+        check_response(48_000);
+    }
+
+    #[test]
+    fn response_44k1_to_16k() {
+        check_response(44_100);
+    }
+
+    #[test]
+    fn flush_completes_the_output() {
+        for &(channels, from_rate, to_rate) in &[(1u16, 48_000u32, 16_000u32), (1, 44_100, 16_000), (2, 44_100, 16_000), (2, 16_000, 48_000)] {
+            for &frames in &[1usize, 7, 441, 1000, 44_101] {
+                let input = vec![0.25; frames * channels as usize];
+                let expected_frames = (frames as u64 * to_rate as u64 + from_rate as u64 - 1) / from_rate as u64;
+
+                let output = resample(&input, channels, from_rate, to_rate);
+                assert_eq!(output.len() as u64, expected_frames * channels as u64,
+                           "{} frames of {} channels from {} to {} Hz", frames, channels, from_rate, to_rate);
+            }
+        }
+    }
+
+    #[test]
+    fn chunking_does_not_change_the_output() {
+        let input: Vec<f32> = Noise::new(37).samples(0.5, 10_000).iter().map(|&sample| sample as f32).collect();
+        let whole = resample(&input, 2, 44_100, 16_000);
+
+        let mut resampler = Resampler::new(2, 44_100, 16_000);
+        let mut chunked = Vec::new();
+        for chunk in input.chunks(2 * 333) {
+            resampler.process(chunk, &mut chunked);
+        }
+        resampler.flush(&mut chunked);
+
+        assert_eq!(whole, chunked);
+    }
+
+    #[test]
+    fn same_rate_is_a_copy() {
+        let input = vec![0.1, -0.2, 0.3, -0.4];
+        assert_eq!(resample(&input, 2, 16_000, 16_000), input);
+    }
+}
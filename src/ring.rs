@@ -0,0 +1,205 @@
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Shared<T> {
+    buffer: Box<[UnsafeCell<T>]>,
+    mask: usize,
+
+    // Running totals of samples pushed and popped; positions in the buffer are these masked.
+    written: AtomicUsize,
+    read: AtomicUsize,
+
+    overruns: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+// Each slot is only ever touched by one side at a time: the producer writes slots between
+// `written` and `read + capacity`, the consumer reads slots between `read` and `written`.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+/// Pushes that didn't fit, and the samples they lost.
+#[derive(Clone, Copy)]
+pub struct Overruns {
+    pub events: usize,
+    pub samples: usize,
+}
+
+/// The writing half of a single-producer, single-consumer ring buffer.
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The reading half of a single-producer, single-consumer ring buffer.
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates a lock-free ring buffer holding at least `capacity` samples.
+///
+/// Neither side ever blocks or allocates, so the producer is safe to use from a real-time
+/// audio callback.
+pub fn channel<T: Copy + Default>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let capacity = capacity.max(1).next_power_of_two();
+    let buffer: Vec<UnsafeCell<T>> = (0..capacity).map(|_| UnsafeCell::new(T::default())).collect();
+
+    let shared = Arc::new(Shared {
+        buffer: buffer.into_boxed_slice(),
+        mask: capacity - 1,
+        written: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+        overruns: AtomicUsize::new(0),
+        dropped: AtomicUsize::new(0),
+    });
+
+    (Producer { shared: shared.clone() }, Consumer { shared: shared })
+}
+
+impl<T: Copy> Producer<T> {
+    /// Queues all of `samples`, or none of them if there isn't room, so interleaved frames are
+    /// never split. Returns whether they were queued; a buffer that didn't fit is counted as an
+    /// overrun.
+    pub fn push(&mut self, samples: &[T]) -> bool {
+        let shared = &*self.shared;
+        let written = shared.written.load(Ordering::Relaxed);
+        let read = shared.read.load(Ordering::Acquire);
+
+        let free = shared.buffer.len() - written.wrapping_sub(read);
+        if samples.len() > free {
+            shared.overruns.fetch_add(1, Ordering::Relaxed);
+            shared.dropped.fetch_add(samples.len(), Ordering::Relaxed);
+            return false;
+        }
+
+        for (offset, &sample) in samples.iter().enumerate() {
+            let slot = written.wrapping_add(offset) & shared.mask;
+            unsafe {
+                *shared.buffer[slot].get() = sample;
+            }
+        }
+
+        shared.written.store(written.wrapping_add(samples.len()), Ordering::Release);
+        true
+    }
+}
+
+impl<T: Copy> Consumer<T> {
+    /// Moves as much of what's queued as fits into `output`, oldest first, returning how many
+    /// samples that was.
+    pub fn pop(&mut self, output: &mut [T]) -> usize {
+        let shared = &*self.shared;
+        let read = shared.read.load(Ordering::Relaxed);
+        let written = shared.written.load(Ordering::Acquire);
+
+        let count = written.wrapping_sub(read).min(output.len());
+        for (offset, sample) in output[..count].iter_mut().enumerate() {
+            let slot = read.wrapping_add(offset) & shared.mask;
+            *sample = unsafe { *shared.buffer[slot].get() };
+        }
+
+        shared.read.store(read.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    pub fn overruns(&self) -> Overruns {
+        Overruns {
+            events: self.shared.overruns.load(Ordering::Relaxed),
+            samples: self.shared.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    #[test]
+    fn capacity_rounds_up_to_a_power_of_two() {
+        let (mut producer, _consumer) = channel::<i16>(5);
+        assert!(producer.push(&[0; 8]));
+        assert!(!producer.push(&[0]));
+    }
+
+    #[test]
+    fn pops_in_order_into_a_fixed_slice() {
+        let (mut producer, mut consumer) = channel::<i16>(8);
+        assert!(producer.push(&[1, 2, 3, 4, 5]));
+
+        let mut block = [0i16; 3];
+        assert_eq!(consumer.pop(&mut block), 3);
+        assert_eq!(block, [1, 2, 3]);
+        assert_eq!(consumer.pop(&mut block), 2);
+        assert_eq!(block[..2], [4, 5]);
+        assert_eq!(consumer.pop(&mut block), 0);
+    }
+
+    #[test]
+    fn wraps_around_the_end_of_the_buffer() {
+        let (mut producer, mut consumer) = channel::<i16>(8);
+        let mut block = [0i16; 8];
+        for round in 0..10 {
+            let samples: Vec<i16> = (0..6).map(|offset| round * 6 + offset).collect();
+            assert!(producer.push(&samples));
+            assert_eq!(consumer.pop(&mut block), 6);
+            assert_eq!(block[..6], samples[..]);
+        }
+    }
+
+    #[test]
+    fn overruns_drop_whole_pushes_and_count_them() {
+        let (mut producer, mut consumer) = channel::<i16>(8);
+        assert!(producer.push(&[1; 6]));
+        assert!(!producer.push(&[2; 3]));
+        assert!(producer.push(&[3; 2]));
+        assert!(!producer.push(&[4; 1]));
+
+        let overruns = consumer.overruns();
+        assert_eq!(overruns.events, 2);
+        assert_eq!(overruns.samples, 4);
+
+        let mut block = [0i16; 16];
+        assert_eq!(consumer.pop(&mut block), 8);
+        assert_eq!(block[..8], [1, 1, 1, 1, 1, 1, 3, 3]);
+    }
+
+    #[test]
+    fn every_sample_is_either_delivered_or_counted() {
+        let (mut producer, mut consumer) = channel::<u32>(256);
+
+        let pushes = 20_000u32;
+        let done = Arc::new(AtomicBool::new(false));
+        let writer_done = done.clone();
+        let writer = thread::spawn(move || {
+            for push in 0..pushes {
+                producer.push(&[push * 3, push * 3 + 1, push * 3 + 2]);
+            }
+            writer_done.store(true, Ordering::Release);
+        });
+
+        let mut received = Vec::new();
+        let mut block = [0u32; 64];
+        loop {
+            let finished = done.load(Ordering::Acquire);
+            let count = consumer.pop(&mut block);
+            received.extend_from_slice(&block[..count]);
+            if finished && count == 0 {
+                break;
+            }
+        }
+        writer.join().unwrap();
+
+        // Pushes arrive whole and in order, with nothing lost that wasn't counted.
+        for frame in received.chunks(3) {
+            assert_eq!(frame[0] % 3, 0);
+            assert_eq!(frame[1], frame[0] + 1);
+            assert_eq!(frame[2], frame[0] + 2);
+        }
+        for pair in received.windows(2) {
+            assert!(pair[1] > pair[0]);
+        }
+        assert_eq!(received.len() + consumer.overruns().samples, pushes as usize * 3);
+    }
+}
@@ -0,0 +1,270 @@
+use base64;
+use serde_json;
+
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use {RecognitionConfig, SpeechRecognitionAlternative};
+
+#[derive(Serialize,Deserialize)]
+pub struct StreamingRecognitionConfig {
+    #[serde(rename = "config")]
+    pub config: RecognitionConfig,
+
+    #[serde(rename = "singleUtterance")]
+    pub single_utterance: bool,
+
+    #[serde(rename = "interimResults")]
+    pub interim_results: bool,
+}
+
+// The first request on a stream carries only the config, every following one only audio.
+#[derive(Serialize,Deserialize)]
+pub struct StreamingRecognizeRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "streamingConfig")]
+    pub streaming_config: Option<StreamingRecognitionConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "audioContent")]
+    pub audio_content: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct StreamingRecognitionResult {
+    #[serde(default)]
+    #[serde(rename = "alternatives")]
+    pub alternatives: Vec<SpeechRecognitionAlternative>,
+
+    #[serde(default)]
+    #[serde(rename = "isFinal")]
+    pub is_final: bool,
+}
+
+#[derive(Deserialize)]
+pub struct StreamingRecognizeResponse {
+    #[serde(default)]
+    #[serde(rename = "results")]
+    pub results: Vec<StreamingRecognitionResult>,
+}
+
+pub type Responses = Box<dyn Iterator<Item = io::Result<StreamingRecognizeResponse>> + Send>;
+
+/// A bidirectional stream of recognition requests and responses.
+///
+/// Requests are sent from the capture thread while responses are read concurrently, so
+/// `responses` hands back an independent reader that may be moved to another thread.
+pub trait StreamingTransport {
+    fn send(&mut self, request: &StreamingRecognizeRequest) -> io::Result<()>;
+
+    /// Signals that no more audio will be sent; the backend flushes its final results.
+    fn finish(&mut self) -> io::Result<()>;
+
+    fn responses(&mut self) -> io::Result<Responses>;
+}
+
+/// Newline-delimited JSON messages over TCP, as spoken by a local streaming bridge or fake server.
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    pub fn connect(address: &str) -> io::Result<TcpTransport> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        Ok(TcpTransport { stream: stream })
+    }
+}
+
+impl StreamingTransport for TcpTransport {
+    fn send(&mut self, request: &StreamingRecognizeRequest) -> io::Result<()> {
+        serde_json::to_writer(&mut self.stream, request)?;
+        self.stream.write_all(b"\n")
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Write)
+    }
+
+    fn responses(&mut self) -> io::Result<Responses> {
+        let reader = BufReader::new(self.stream.try_clone()?);
+        Ok(Box::new(reader.lines().map(|line| {
+            line.and_then(|line| serde_json::from_str(&line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)))
+        })))
+    }
+}
+
+// Returns the final transcripts, in order.
+fn print_responses<W: Write>(responses: Responses, out: &mut W) -> io::Result<Vec<String>> {
+    let mut finals = Vec::new();
+    let mut interim_len: usize = 0;
+
+    for response in responses {
+        for result in response?.results {
+            if let Some(alternative) = result.alternatives.first() {
+                // Interim transcripts are rewritten in place until the result is final.
+                let padding = " ".repeat(interim_len.saturating_sub(alternative.transcript.len()));
+                if result.is_final {
+                    writeln!(out, "\r{}{}", alternative.transcript, padding)?;
+                    finals.push(alternative.transcript.clone());
+                    interim_len = 0;
+                } else {
+                    write!(out, "\r{}{}", alternative.transcript, padding)?;
+                    interim_len = alternative.transcript.len();
+                }
+                out.flush()?;
+            }
+        }
+    }
+
+    if interim_len > 0 {
+        writeln!(out)?;
+    }
+
+    Ok(finals)
+}
+
+/// Streams mono LINEAR16 chunks to the transport as they arrive, printing transcripts live.
+///
+/// Returns the final transcripts once `chunks` is closed by the capture side and the backend has
+/// sent its last result.
+pub fn run_session<T: StreamingTransport>(transport: &mut T, config: StreamingRecognitionConfig, chunks: Receiver<Vec<i16>>) -> io::Result<Vec<String>> {
+    transport.send(&StreamingRecognizeRequest {
+        streaming_config: Some(config),
+        audio_content: None,
+    })?;
+
+    let responses = transport.responses()?;
+    let printer = thread::spawn(move || print_responses(responses, &mut io::stdout()));
+
+    for chunk in chunks.iter() {
+        let mut bytes = Vec::with_capacity(chunk.len() * 2);
+        for sample in chunk {
+            bytes.push(sample as u8);
+            bytes.push((sample >> 8) as u8);
+        }
+
+        transport.send(&StreamingRecognizeRequest {
+            streaming_config: None,
+            audio_content: Some(base64::encode(&bytes)),
+        })?;
+    }
+
+    transport.finish()?;
+
+    match printer.join() {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Transcript printer panicked")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread::JoinHandle;
+
+    use RecognitionConfig;
+
+    fn config() -> StreamingRecognitionConfig {
+        StreamingRecognitionConfig {
+            config: RecognitionConfig {
+                encoding: String::from("LINEAR16"),
+                sample_rate_hz: 16000.0,
+                language: String::from("en-US"),
+                max_alternatives: 1,
+                profanity_filter: false,
+                contexts: vec![],
+                enable_word_time_offsets: false,
+                diarization_config: None,
+                enable_automatic_punctuation: false,
+                model: None,
+                use_enhanced: false,
+                audio_channel_count: 1,
+                enable_separate_recognition_per_channel: false,
+            },
+            single_utterance: false,
+            interim_results: true,
+        }
+    }
+
+    fn result(transcript: &str, is_final: bool) -> String {
+        format!("{{\"results\": [{{\"alternatives\": [{{\"transcript\": \"{}\"}}], \"isFinal\": {}}}]}}\n", transcript, is_final)
+    }
+
+    // A fake streaming bridge: answers the config with interim and final results, then sends one
+    // more final result only once the client has finished sending. Returns every request line.
+    fn serve() -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+
+            let mut requests = vec![lines.next().unwrap().unwrap()];
+            for response in &[result("hel", false), result("hello", false), result("hello there", true), result("gen", false)] {
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+
+            requests.extend(lines.map(|line| line.unwrap()));
+            stream.write_all(result("general kenobi", true).as_bytes()).unwrap();
+            requests
+        });
+
+        (address, server)
+    }
+
+    fn decode(request: &str) -> Vec<i16> {
+        let request: StreamingRecognizeRequest = serde_json::from_str(request).unwrap();
+        assert!(request.streaming_config.is_none());
+        let bytes = base64::decode(&request.audio_content.unwrap()).unwrap();
+        bytes.chunks(2).map(|pair| (pair[0] as u16 | (pair[1] as u16) << 8) as i16).collect()
+    }
+
+    #[test]
+    fn streams_audio_and_collects_final_transcripts() {
+        let (address, server) = serve();
+        let mut transport = TcpTransport::connect(&address).unwrap();
+
+        let (sender, chunks) = mpsc::channel();
+        sender.send(vec![0, 1, -1, i16::max_value()]).unwrap();
+        sender.send(vec![i16::min_value(), 256]).unwrap();
+        drop(sender);
+
+        let finals = run_session(&mut transport, config(), chunks).unwrap();
+        assert_eq!(finals, vec!["hello there", "general kenobi"]);
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+
+        let first: StreamingRecognizeRequest = serde_json::from_str(&requests[0]).unwrap();
+        assert!(first.audio_content.is_none());
+        assert!(first.streaming_config.unwrap().interim_results);
+
+        assert_eq!(decode(&requests[1]), vec![0, 1, -1, i16::max_value()]);
+        assert_eq!(decode(&requests[2]), vec![i16::min_value(), 256]);
+    }
+
+    #[test]
+    fn rewrites_interim_transcripts_in_place() {
+        let responses: Vec<io::Result<StreamingRecognizeResponse>> = [result("hel", false), result("hello", false), result("hi", true), result("bye", false)]
+            .iter()
+            .map(|line| Ok(serde_json::from_str(line).unwrap()))
+            .collect();
+
+        let mut out = Vec::new();
+        let finals = print_responses(Box::new(responses.into_iter()), &mut out).unwrap();
+
+        assert_eq!(finals, vec!["hi"]);
+        // A shorter final transcript blanks out what's left of the interim one, and an unfinished
+        // interim transcript still ends its line.
+        assert_eq!(String::from_utf8(out).unwrap(), "\rhel\rhello\rhi   \n\rbye\n");
+    }
+}
@@ -0,0 +1,49 @@
+use std::f64::consts::PI;
+
+/// `frames` samples of a sine wave starting at `phase` radians.
+pub fn sine(frequency: f64, amplitude: f64, phase: f64, sample_rate: u32, frames: usize) -> Vec<f64> {
+    (0..frames)
+        .map(|n| amplitude * (2.0 * PI * frequency * n as f64 / sample_rate as f64 + phase).sin())
+        .collect()
+}
+
+/// Interleaves one signal per channel, stopping at the shortest.
+pub fn interleave(channels: &[Vec<f64>]) -> Vec<f64> {
+    let frames = channels.iter().map(|channel| channel.len()).min().unwrap_or(0);
+    let mut samples = Vec::with_capacity(frames * channels.len());
+    for frame in 0..frames {
+        for channel in channels {
+            samples.push(channel[frame]);
+        }
+    }
+    samples
+}
+
+/// A xorshift generator, so noise is the same on every run.
+pub struct Noise {
+    state: u32,
+}
+
+impl Noise {
+    pub fn new(seed: u32) -> Noise {
+        assert!(seed != 0, "xorshift never leaves a zero state");
+        Noise { state: seed }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    /// Uniform in [-1, 1].
+    pub fn uniform(&mut self) -> f64 {
+        self.next_u32() as f64 / u32::max_value() as f64 * 2.0 - 1.0
+    }
+
+    /// `count` uniform samples scaled to `amplitude`.
+    pub fn samples(&mut self, amplitude: f64, count: usize) -> Vec<f64> {
+        (0..count).map(|_| amplitude * self.uniform()).collect()
+    }
+}
@@ -0,0 +1,318 @@
+use serde_json;
+
+use std::cmp::Ordering;
+
+use {RecognizeResponse, SpeechRecognitionResult, SpeechRecognitionWordInfo};
+
+/// A stretch of transcript attributed to a single speaker (or to no one, without diarization).
+#[derive(Serialize)]
+pub struct Turn {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "channel")]
+    pub channel: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "speaker")]
+    pub speaker: Option<i32>,
+
+    #[serde(rename = "start")]
+    pub start_secs: f32,
+
+    #[serde(rename = "end")]
+    pub end_secs: f32,
+
+    #[serde(rename = "transcript")]
+    pub transcript: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "confidence")]
+    pub confidence: Option<f32>,
+}
+
+// Durations are sent as decimal seconds with an "s" suffix, e.g. "1.500s".
+fn parse_offset(offset: &str) -> f32 {
+    offset.trim_end_matches('s').parse().unwrap_or(0.0)
+}
+
+fn speaker_turns(channel: Option<i32>, words: &[SpeechRecognitionWordInfo]) -> Vec<Turn> {
+    let mut turns: Vec<Turn> = Vec::new();
+
+    for word in words {
+        let start_secs = parse_offset(&word.start_time);
+        let end_secs = parse_offset(&word.end_time);
+
+        let continues = match turns.last() {
+            Some(turn) => turn.speaker == Some(word.speaker_tag),
+            None => false,
+        };
+
+        if continues {
+            let turn = turns.last_mut().unwrap();
+            turn.transcript.push(' ');
+            turn.transcript.push_str(&word.word);
+            turn.end_secs = end_secs;
+        } else {
+            turns.push(Turn {
+                channel: channel,
+                speaker: Some(word.speaker_tag),
+                start_secs: start_secs,
+                end_secs: end_secs,
+                transcript: word.word.clone(),
+                confidence: None,
+            });
+        }
+    }
+
+    turns
+}
+
+fn is_diarized(result: &SpeechRecognitionResult) -> bool {
+    match result.alternatives.first() {
+        Some(alternative) => alternative.words.iter().any(|word| word.speaker_tag != 0),
+        None => false,
+    }
+}
+
+/// Splits a recognition response into turns.
+///
+/// With diarization the service repeats every word of a channel, tagged with its speaker, in
+/// that channel's last result, so turns are rebuilt from those words and the channels merged
+/// in time order. Otherwise each result is a turn.
+pub fn turns(response: &RecognizeResponse) -> Vec<Turn> {
+    let mut diarized: Vec<&SpeechRecognitionResult> = Vec::new();
+    for result in response.results.iter().filter(|result| is_diarized(result)) {
+        match diarized.iter().position(|last| last.channel_tag == result.channel_tag) {
+            Some(index) => diarized[index] = result,
+            None => diarized.push(result),
+        }
+    }
+
+    if !diarized.is_empty() {
+        let mut turns: Vec<Turn> = diarized.iter()
+            .flat_map(|result| {
+                let channel = if result.channel_tag != 0 { Some(result.channel_tag) } else { None };
+                speaker_turns(channel, &result.alternatives[0].words)
+            })
+            .collect();
+        turns.sort_by(|a, b| a.start_secs.partial_cmp(&b.start_secs).unwrap_or(Ordering::Equal));
+        return turns;
+    }
+
+    // With separate recognition per channel, each result is tagged with its channel.
+    response.results.iter()
+        .filter_map(|result| result.alternatives.first().map(|alternative| (result.channel_tag, alternative)))
+        .map(|(channel_tag, alternative)| Turn {
+            channel: if channel_tag != 0 { Some(channel_tag) } else { None },
+            speaker: None,
+            start_secs: alternative.words.first().map(|word| parse_offset(&word.start_time)).unwrap_or(0.0),
+            end_secs: alternative.words.last().map(|word| parse_offset(&word.end_time)).unwrap_or(0.0),
+            transcript: alternative.transcript.trim().to_string(),
+            confidence: Some(alternative.confidence),
+        })
+        .collect()
+}
+
+fn label(turn: &Turn) -> String {
+    match (turn.channel, turn.speaker) {
+        (Some(channel), Some(speaker)) => format!("Channel {}, Speaker {}: {}", channel, speaker, turn.transcript),
+        (Some(channel), None) => format!("Channel {}: {}", channel, turn.transcript),
+        (None, Some(speaker)) => format!("Speaker {}: {}", speaker, turn.transcript),
+        (None, None) => turn.transcript.clone(),
+    }
+}
+
+pub fn render_text(turns: &[Turn]) -> String {
+    let mut text = String::new();
+    for turn in turns {
+        text += &label(turn);
+        if let Some(confidence) = turn.confidence {
+            text += &format!(" (Confidence: {})", confidence);
+        }
+        text += "\n";
+    }
+    text
+}
+
+pub fn render_json(turns: &[Turn]) -> String {
+    serde_json::to_string_pretty(turns).unwrap()
+}
+
+fn srt_timestamp(secs: f32) -> String {
+    let millis = (secs * 1000.0).round() as u64;
+    format!("{:02}:{:02}:{:02},{:03}", millis / 3_600_000, (millis / 60_000) % 60, (millis / 1000) % 60, millis % 1000)
+}
+
+/// SubRip subtitles, one cue per turn.
+pub fn render_srt(turns: &[Turn]) -> String {
+    let mut srt = String::new();
+    for (index, turn) in turns.iter().enumerate() {
+        srt += &format!("{}\n{} --> {}\n{}\n\n", index + 1, srt_timestamp(turn.start_secs), srt_timestamp(turn.end_secs), label(turn));
+    }
+    srt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(json: &str) -> RecognizeResponse {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn word(word: &str, start: &str, end: &str, speaker: i32) -> String {
+        format!(r#"{{"word": "{}", "startTime": "{}", "endTime": "{}", "speakerTag": {}}}"#, word, start, end, speaker)
+    }
+
+    fn diarized(channel: i32, words: &[String]) -> String {
+        format!(r#"{{"channelTag": {}, "alternatives": [{{"transcript": "", "words": [{}]}}]}}"#, channel, words.join(", "))
+    }
+
+    fn summary(turns: &[Turn]) -> Vec<(Option<i32>, Option<i32>, f32, f32, &str)> {
+        turns.iter().map(|turn| (turn.channel, turn.speaker, turn.start_secs, turn.end_secs, &turn.transcript[..])).collect()
+    }
+
+    fn conversation() -> Vec<Turn> {
+        turns(&response(&format!(r#"{{"results": [
+            {{"alternatives": [{{"transcript": "hello there hi", "confidence": 0.9}}]}},
+            {{"alternatives": [{{"transcript": "again", "confidence": 0.8}}]}},
+            {}
+        ]}}"#, diarized(0, &[
+            word("hello", "0s", "0.500s", 1),
+            word("there", "0.500s", "1s", 1),
+            word("hi", "1.200s", "1.500s", 2),
+            word("again", "2s", "2.500s", 1),
+        ]))))
+    }
+
+    #[test]
+    fn diarized_words_are_grouped_into_speaker_turns() {
+        let turns = conversation();
+        assert_eq!(summary(&turns), vec![
+            (None, Some(1), 0.0, 1.0, "hello there"),
+            (None, Some(2), 1.2, 1.5, "hi"),
+            (None, Some(1), 2.0, 2.5, "again"),
+        ]);
+        assert!(turns.iter().all(|turn| turn.confidence.is_none()));
+    }
+
+    #[test]
+    fn every_diarized_channel_is_kept() {
+        // Each channel's last result repeats all of its words; the earlier one is superseded.
+        let turns = turns(&response(&format!(r#"{{"results": [{}, {}, {}]}}"#,
+            diarized(1, &[word("hello", "0s", "0.500s", 1)]),
+            diarized(2, &[word("hi", "1s", "1.250s", 1), word("there", "3s", "3.500s", 2)]),
+            diarized(1, &[word("hello", "0s", "0.500s", 1), word("bye", "2s", "2.500s", 2)]),
+        )));
+
+        assert_eq!(summary(&turns), vec![
+            (Some(1), Some(1), 0.0, 0.5, "hello"),
+            (Some(2), Some(1), 1.0, 1.25, "hi"),
+            (Some(1), Some(2), 2.0, 2.5, "bye"),
+            (Some(2), Some(2), 3.0, 3.5, "there"),
+        ]);
+    }
+
+    #[test]
+    fn results_without_speakers_are_turns() {
+        let turns = turns(&response(r#"{"results": [
+            {"alternatives": [{"transcript": " hello there ", "confidence": 0.9, "words": [
+                {"word": "hello", "startTime": "0.100s", "endTime": "0.400s"},
+                {"word": "there", "startTime": "0.400s", "endTime": "0.900s"}
+            ]}]},
+            {"alternatives": [{"transcript": "no words", "confidence": 0.5}]},
+            {"alternatives": []}
+        ]}"#));
+
+        assert_eq!(summary(&turns), vec![
+            (None, None, 0.1, 0.9, "hello there"),
+            (None, None, 0.0, 0.0, "no words"),
+        ]);
+        assert_eq!(turns[0].confidence, Some(0.9));
+        assert!(super::turns(&response("{}")).is_empty());
+    }
+
+    #[test]
+    fn renders_text() {
+        assert_eq!(render_text(&conversation()), "Speaker 1: hello there\nSpeaker 2: hi\nSpeaker 1: again\n");
+
+        let plain = turns(&response(r#"{"results": [{"alternatives": [{"transcript": "hello", "confidence": 0.75}]}]}"#));
+        assert_eq!(render_text(&plain), "hello (Confidence: 0.75)\n");
+    }
+
+    #[test]
+    fn renders_json_without_missing_fields() {
+        let json: serde_json::Value = serde_json::from_str(&render_json(&conversation()[1..2])).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(r#"[{"speaker": 2, "start": 1.2, "end": 1.5, "transcript": "hi"}]"#).unwrap();
+        assert_eq!(json, expected);
+    }
+
+    #[test]
+    fn renders_numbered_srt_cues() {
+        assert_eq!(render_srt(&conversation()), "\
+1
+00:00:00,000 --> 00:00:01,000
+Speaker 1: hello there
+
+2
+00:00:01,200 --> 00:00:01,500
+Speaker 2: hi
+
+3
+00:00:02,000 --> 00:00:02,500
+Speaker 1: again
+
+");
+        assert_eq!(render_srt(&[]), "");
+    }
+
+    #[test]
+    fn srt_timestamps_roll_over_and_round() {
+        assert_eq!(srt_timestamp(0.0), "00:00:00,000");
+        assert_eq!(srt_timestamp(1.0006), "00:00:01,001");
+        assert_eq!(srt_timestamp(1.0004), "00:00:01,000");
+        assert_eq!(srt_timestamp(59.9996), "00:01:00,000");
+        assert_eq!(srt_timestamp(3599.999), "00:59:59,999");
+        assert_eq!(srt_timestamp(3599.9996), "01:00:00,000");
+        assert_eq!(srt_timestamp(3723.25), "01:02:03,250");
+        assert_eq!(srt_timestamp(36_000.0), "10:00:00,000");
+    }
+
+    fn stereo() -> Vec<Turn> {
+        turns(&response(r#"{"results": [
+            {"channelTag": 1, "alternatives": [{"transcript": "left side", "confidence": 0.5, "words": [
+                {"word": "left", "startTime": "0s", "endTime": "0.250s"},
+                {"word": "side", "startTime": "0.250s", "endTime": "0.750s"}
+            ]}]},
+            {"channelTag": 2, "alternatives": [{"transcript": "right side", "confidence": 0.25}]}
+        ]}"#))
+    }
+
+    #[test]
+    fn channel_results_are_labelled_by_channel() {
+        let turns = stereo();
+        assert_eq!(summary(&turns), vec![
+            (Some(1), None, 0.0, 0.75, "left side"),
+            (Some(2), None, 0.0, 0.0, "right side"),
+        ]);
+
+        assert_eq!(render_text(&turns), "Channel 1: left side (Confidence: 0.5)\nChannel 2: right side (Confidence: 0.25)\n");
+        assert_eq!(render_srt(&turns[..1]), "1\n00:00:00,000 --> 00:00:00,750\nChannel 1: left side\n\n");
+
+        let json: serde_json::Value = serde_json::from_str(&render_json(&turns[1..])).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(r#"[{"channel": 2, "start": 0.0, "end": 0.0, "transcript": "right side", "confidence": 0.25}]"#).unwrap();
+        assert_eq!(json, expected);
+    }
+
+    #[test]
+    fn diarized_channels_are_labelled_with_both() {
+        let turns = turns(&response(&format!(r#"{{"results": [{}]}}"#, diarized(2, &[word("hi", "1s", "1.500s", 3)]))));
+        assert_eq!(render_text(&turns), "Channel 2, Speaker 3: hi\n");
+    }
+
+    #[test]
+    fn offsets_without_a_suffix_or_value_parse() {
+        assert_eq!(parse_offset("1.500s"), 1.5);
+        assert_eq!(parse_offset("2"), 2.0);
+        assert_eq!(parse_offset(""), 0.0);
+    }
+}
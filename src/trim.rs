@@ -0,0 +1,250 @@
+use hound;
+
+use std::path::Path;
+
+use convert::{self, ConvertError, SampleReader};
+
+const BLOCK_FRAMES: usize = 4096;
+
+pub struct TrimOptions {
+    /// Frames whose loudest sample is below this, in dBFS, are silence.
+    pub threshold_db: f32,
+
+    /// Silence kept either side of the audio, so onsets and decays aren't cut.
+    pub padding_secs: f32,
+}
+
+impl Default for TrimOptions {
+    fn default() -> TrimOptions {
+        TrimOptions {
+            threshold_db: -50.0,
+            padding_secs: 0.1,
+        }
+    }
+}
+
+/// What trimming removed from a file, in seconds.
+pub struct Trimmed {
+    pub leading_secs: f32,
+    pub trailing_secs: f32,
+
+    /// Nothing rose above the threshold, so the file was left whole.
+    pub silent: bool,
+}
+
+// The first audible frame and the one after the last, if any.
+fn audible_frames<P: AsRef<Path>>(path: P, threshold: f32) -> Result<(Option<(u64, u64)>, u64), ConvertError> {
+    let mut reader = SampleReader::open(path)?;
+    let channels = reader.spec().channels as usize;
+
+    let mut audible = None;
+    let mut position = 0u64;
+    let mut block = Vec::new();
+    while reader.read_block(&mut block, BLOCK_FRAMES)? > 0 {
+        for frame in block.chunks(channels) {
+            if frame.iter().any(|sample| sample.abs() >= threshold) {
+                audible = match audible {
+                    Some((start, _)) => Some((start, position + 1)),
+                    None => Some((position, position + 1)),
+                };
+            }
+            position += 1;
+        }
+    }
+
+    Ok((audible, position))
+}
+
+/// Copies `input` to `output` without its leading and trailing silence.
+///
+/// Samples are copied exactly, in the input's own format; only whole frames are dropped.
+pub fn trim<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q, options: &TrimOptions) -> Result<Trimmed, ConvertError> {
+    // The source is read a second time while the output is written.
+    if convert::same_file(input.as_ref(), output.as_ref()) {
+        return Err(ConvertError::Unsupported(String::from("trimming a file onto itself")));
+    }
+
+    let threshold = 10f32.powf(options.threshold_db / 20.0);
+    let (audible, frames) = audible_frames(&input, threshold)?;
+
+    let reader = hound::WavReader::open(&input)?;
+    let spec = reader.spec();
+    let padding = (options.padding_secs * spec.sample_rate as f32) as u64;
+
+    let (start, end) = match audible {
+        Some((start, end)) => (start.saturating_sub(padding), (end + padding).min(frames)),
+        None => (0, frames),
+    };
+
+    let channels = spec.channels as u64;
+    let skip = (start * channels) as usize;
+    let take = ((end - start) * channels) as usize;
+
+    let mut writer = hound::WavWriter::create(&output, spec)?;
+    match spec.sample_format {
+        hound::SampleFormat::Float => {
+            for sample in reader.into_samples::<f32>().skip(skip).take(take) {
+                writer.write_sample(sample?)?;
+            }
+        },
+        hound::SampleFormat::Int => {
+            for sample in reader.into_samples::<i32>().skip(skip).take(take) {
+                writer.write_sample(sample?)?;
+            }
+        },
+    }
+    writer.finalize()?;
+
+    Ok(Trimmed {
+        leading_secs: start as f32 / spec.sample_rate as f32,
+        trailing_secs: (frames - end) as f32 / spec.sample_rate as f32,
+        silent: audible.is_none(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    const RATE: u32 = 1000;
+
+    fn spec(channels: u16) -> hound::WavSpec {
+        hound::WavSpec {
+            channels: channels,
+            sample_rate: RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        }
+    }
+
+    fn trim_samples(samples: &[i16], channels: u16, options: &TrimOptions) -> (Trimmed, Vec<i16>) {
+        let input_file = tempfile::NamedTempFile::new().unwrap();
+        let output_file = tempfile::NamedTempFile::new().unwrap();
+
+        let mut writer = hound::WavWriter::create(input_file.path(), spec(channels)).unwrap();
+        for &sample in samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let trimmed = match trim(input_file.path(), output_file.path(), options) {
+            Ok(trimmed) => trimmed,
+            Err(err) => panic!("trimming failed: {}", err),
+        };
+        let mut reader = hound::WavReader::open(output_file.path()).unwrap();
+        assert_eq!(reader.spec(), spec(channels));
+        (trimmed, reader.samples::<i16>().map(|sample| sample.unwrap()).collect())
+    }
+
+    fn no_padding(threshold_db: f32) -> TrimOptions {
+        TrimOptions {
+            threshold_db: threshold_db,
+            padding_secs: 0.0,
+        }
+    }
+
+    // `leading` quiet frames, then `loud` loud ones, then `trailing` quiet ones.
+    fn burst(leading: usize, loud: usize, trailing: usize, quiet: i16) -> Vec<i16> {
+        let mut samples = vec![quiet; leading];
+        samples.extend((0..loud).map(|i| if i % 2 == 0 { 8000 } else { -8000 }));
+        samples.extend(vec![quiet; trailing]);
+        samples
+    }
+
+    #[test]
+    fn cuts_frames_below_the_threshold() {
+        // 30 is about -61 dBFS, under the -50 dBFS default but over -70 dBFS.
+        let input = burst(200, 100, 300, 30);
+
+        let (trimmed, output) = trim_samples(&input, 1, &no_padding(-50.0));
+        assert_eq!(output, &input[200..300]);
+        assert!((trimmed.leading_secs - 0.2).abs() < 1e-6);
+        assert!((trimmed.trailing_secs - 0.3).abs() < 1e-6);
+        assert!(!trimmed.silent);
+
+        let (trimmed, output) = trim_samples(&input, 1, &no_padding(-70.0));
+        assert_eq!(output, input);
+        assert_eq!(trimmed.leading_secs, 0.0);
+        assert_eq!(trimmed.trailing_secs, 0.0);
+    }
+
+    #[test]
+    fn keeps_padding_either_side() {
+        let input = burst(200, 100, 300, 0);
+        let options = TrimOptions {
+            threshold_db: -50.0,
+            padding_secs: 0.05,
+        };
+
+        let (trimmed, output) = trim_samples(&input, 1, &options);
+        assert_eq!(output, &input[150..350]);
+        assert!((trimmed.leading_secs - 0.15).abs() < 1e-6);
+        assert!((trimmed.trailing_secs - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn padding_stops_at_the_ends_of_the_file() {
+        let input = burst(20, 100, 30, 0);
+        let options = TrimOptions {
+            threshold_db: -50.0,
+            padding_secs: 0.05,
+        };
+
+        let (trimmed, output) = trim_samples(&input, 1, &options);
+        assert_eq!(output, input);
+        assert_eq!(trimmed.leading_secs, 0.0);
+        assert_eq!(trimmed.trailing_secs, 0.0);
+    }
+
+    #[test]
+    fn leaves_a_silent_file_whole() {
+        let input = vec![10i16; 500];
+
+        let (trimmed, output) = trim_samples(&input, 1, &no_padding(-50.0));
+        assert!(trimmed.silent);
+        assert_eq!(output, input);
+        assert_eq!(trimmed.leading_secs, 0.0);
+        assert_eq!(trimmed.trailing_secs, 0.0);
+    }
+
+    #[test]
+    fn keeps_whole_frames_when_one_channel_is_audible() {
+        // Only the right channel carries audio; the left stays silent throughout.
+        let mut input = Vec::new();
+        for frame in 0..400 {
+            let right = if frame >= 100 && frame < 250 { 8000 } else { 0 };
+            input.push(frame as i16 % 7);
+            input.push(right);
+        }
+
+        let (trimmed, output) = trim_samples(&input, 2, &no_padding(-50.0));
+        assert_eq!(output, &input[200..500]);
+        assert_eq!(output[0], 100 % 7);
+        assert_eq!(output[1], 8000);
+        assert!((trimmed.leading_secs - 0.1).abs() < 1e-6);
+        assert!((trimmed.trailing_secs - 0.15).abs() < 1e-6);
+    }
+
+    #[test]
+    fn refuses_to_trim_a_file_onto_itself() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let input = burst(200, 100, 300, 0);
+        let mut writer = hound::WavWriter::create(file.path(), spec(1)).unwrap();
+        for &sample in &input {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        match trim(file.path(), file.path(), &TrimOptions::default()) {
+            Err(ConvertError::Unsupported(_)) => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("trimmed a file onto itself"),
+        }
+
+        // The source is untouched.
+        let mut reader = hound::WavReader::open(file.path()).unwrap();
+        let samples: Vec<i16> = reader.samples::<i16>().map(|sample| sample.unwrap()).collect();
+        assert_eq!(samples, input);
+    }
+}
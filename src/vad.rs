@@ -0,0 +1,358 @@
+use cpal;
+
+use std::collections::VecDeque;
+
+// Length of the windows speech is detected in.
+const WINDOW_SECS: f32 = 0.01;
+
+// Unvoiced consonants like "s" and "f" are quiet but cross zero often, so windows up to this far
+// below the threshold still count as speech when they cross zero at least `FRICATIVE_CROSSINGS`
+// times a second.
+const FRICATIVE_MARGIN_DB: f32 = 10.0;
+const FRICATIVE_CROSSINGS: f32 = 3000.0;
+
+// Loud audio crossing zero less often than this is mains hum or rumble rather than a voice:
+// 50 and 60 Hz hum cross 100 and 120 times a second, while even a deep voice's fundamental is
+// above 75 Hz, and its formants only add crossings. Measured over several windows, since one
+// holds less than a cycle of the lowest voices.
+const HUM_CROSSINGS: f32 = 150.0;
+const HUM_WINDOWS: usize = 5;
+
+/// Tuning for the voice gate. Durations are in seconds.
+pub struct VadConfig {
+    /// Windows quieter than this, in dBFS, are silence.
+    pub threshold_db: f32,
+
+    /// How long speech has to last before capture starts, so clicks and bumps are ignored.
+    pub min_speech_secs: f32,
+
+    /// How much silence after speech ends the capture.
+    pub trailing_silence_secs: f32,
+
+    /// Audio kept from before speech was detected, so the first syllable isn't clipped.
+    pub pre_roll_secs: f32,
+}
+
+impl Default for VadConfig {
+    fn default() -> VadConfig {
+        VadConfig {
+            threshold_db: -40.0,
+            min_speech_secs: 0.05,
+            trailing_silence_secs: 1.0,
+            pre_roll_secs: 0.3,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VadEvent {
+    SpeechStarted,
+    SpeechEnded,
+}
+
+enum State {
+    Waiting,
+    Speaking,
+    Ended,
+}
+
+/// Energy and zero-crossing voice activity detection over interleaved capture buffers.
+///
+/// Nothing passes the gate until speech starts; then the pre-roll and everything after it does,
+/// up to and including the trailing silence that ends it. Once ended the gate stays closed.
+pub struct Gate<T> {
+    channels: usize,
+    sample_rate: f32,
+    window_frames: usize,
+    min_speech_windows: usize,
+    trailing_silence_windows: usize,
+    threshold_db: f32,
+
+    // Recent samples while waiting, covering the pre-roll plus the speech needed to start.
+    pre_roll: VecDeque<T>,
+    pre_roll_samples: usize,
+
+    state: State,
+    speech_windows: usize,
+    silence_windows: usize,
+
+    // The window being measured, on the channels' mean.
+    frames: usize,
+    energy: f64,
+    crossings: usize,
+    positive: bool,
+
+    // Zero crossings of the last `HUM_WINDOWS` windows.
+    recent_crossings: VecDeque<usize>,
+}
+
+fn windows(secs: f32) -> usize {
+    (secs / WINDOW_SECS).round().max(1.0) as usize
+}
+
+impl<T: cpal::Sample> Gate<T> {
+    pub fn new(config: &VadConfig, channels: u16, sample_rate: u32) -> Gate<T> {
+        let window_frames = ((sample_rate as f32 * WINDOW_SECS) as usize).max(1);
+        let min_speech_windows = windows(config.min_speech_secs);
+        let pre_roll_frames = (config.pre_roll_secs * sample_rate as f32) as usize + min_speech_windows * window_frames;
+
+        Gate {
+            channels: channels as usize,
+            sample_rate: sample_rate as f32,
+            window_frames: window_frames,
+            min_speech_windows: min_speech_windows,
+            trailing_silence_windows: windows(config.trailing_silence_secs),
+            threshold_db: config.threshold_db,
+            pre_roll: VecDeque::with_capacity(pre_roll_frames * channels as usize),
+            pre_roll_samples: pre_roll_frames * channels as usize,
+            state: State::Waiting,
+            speech_windows: 0,
+            silence_windows: 0,
+            frames: 0,
+            energy: 0.0,
+            crossings: 0,
+            positive: true,
+            recent_crossings: VecDeque::with_capacity(HUM_WINDOWS),
+        }
+    }
+
+    /// Appends whatever part of `input` passes the gate to `output`, reporting when speech
+    /// starts or ends. An end is reported in preference to a start in the same buffer.
+    pub fn process(&mut self, input: &[T], output: &mut Vec<T>) -> Option<VadEvent> {
+        let mut event = None;
+
+        for frame in input.chunks(self.channels) {
+            match self.state {
+                State::Waiting => {
+                    self.pre_roll.extend(frame.iter().cloned());
+                    while self.pre_roll.len() > self.pre_roll_samples {
+                        self.pre_roll.pop_front();
+                    }
+                },
+                State::Speaking => output.extend_from_slice(frame),
+                State::Ended => break,
+            }
+
+            let level = frame.iter().map(|sample| sample.to_f32()).sum::<f32>() / frame.len() as f32;
+            self.energy += (level * level) as f64;
+            if (level >= 0.0) != self.positive {
+                self.positive = level >= 0.0;
+                self.crossings += 1;
+            }
+
+            self.frames += 1;
+            if self.frames == self.window_frames {
+                if let Some(ended) = self.end_window(output) {
+                    event = Some(ended);
+                }
+            }
+        }
+
+        event
+    }
+
+    fn is_speech(&self) -> bool {
+        let level_db = 10.0 * (self.energy / self.frames as f64).max(1e-20).log10() as f32;
+        let crossings_per_sec = self.crossings as f32 * self.sample_rate / self.frames as f32;
+
+        if level_db >= self.threshold_db {
+            let recent_frames = self.recent_crossings.len() * self.window_frames;
+            let recent_crossings: usize = self.recent_crossings.iter().sum();
+            recent_crossings as f32 * self.sample_rate / recent_frames as f32 >= HUM_CROSSINGS
+        } else {
+            level_db >= self.threshold_db - FRICATIVE_MARGIN_DB && crossings_per_sec >= FRICATIVE_CROSSINGS
+        }
+    }
+
+    fn end_window(&mut self, output: &mut Vec<T>) -> Option<VadEvent> {
+        if self.recent_crossings.len() == HUM_WINDOWS {
+            self.recent_crossings.pop_front();
+        }
+        self.recent_crossings.push_back(self.crossings);
+
+        let speech = self.is_speech();
+        self.frames = 0;
+        self.energy = 0.0;
+        self.crossings = 0;
+
+        match self.state {
+            State::Waiting => {
+                if !speech {
+                    self.speech_windows = 0;
+                    return None;
+                }
+                self.speech_windows += 1;
+                if self.speech_windows < self.min_speech_windows {
+                    return None;
+                }
+                output.extend(self.pre_roll.drain(..));
+                self.state = State::Speaking;
+                Some(VadEvent::SpeechStarted)
+            },
+            State::Speaking => {
+                if speech {
+                    self.silence_windows = 0;
+                    return None;
+                }
+                self.silence_windows += 1;
+                if self.silence_windows < self.trailing_silence_windows {
+                    return None;
+                }
+                self.state = State::Ended;
+                Some(VadEvent::SpeechEnded)
+            },
+            State::Ended => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_signal::{self, Noise};
+
+    const RATE: u32 = 16_000;
+
+    fn frames(secs: f32) -> usize {
+        (secs * RATE as f32).round() as usize
+    }
+
+    fn tone(frequency: f32, secs: f32, peak_db: f32) -> Vec<f32> {
+        let amplitude = 10f64.powf(peak_db as f64 / 20.0);
+        test_signal::sine(frequency as f64, amplitude, 0.0, RATE, frames(secs)).iter().map(|&sample| sample as f32).collect()
+    }
+
+    // Uniform white noise at an RMS level, from a fixed seed.
+    fn noise(secs: f32, rms_db: f32, seed: u32) -> Vec<f32> {
+        let amplitude = 10f64.powf(rms_db as f64 / 20.0) * 3f64.sqrt();
+        Noise::new(seed).samples(amplitude, frames(secs)).iter().map(|&sample| sample as f32).collect()
+    }
+
+    // Noise quiet enough to be silence, so what the gate passes can be told apart.
+    fn room(secs: f32) -> Vec<f32> {
+        noise(secs, -70.0, 99)
+    }
+
+    fn concat(parts: &[Vec<f32>]) -> Vec<f32> {
+        parts.iter().flat_map(|part| part.iter().cloned()).collect()
+    }
+
+    // Feeds the gate in buffers that don't line up with its windows, as a capture callback would.
+    fn run(input: &[f32]) -> (Vec<(usize, VadEvent)>, Vec<f32>) {
+        let mut gate = Gate::new(&VadConfig::default(), 1, RATE);
+        let mut events = Vec::new();
+        let mut output = Vec::new();
+        let mut position = 0;
+        for buffer in input.chunks(333) {
+            position += buffer.len();
+            if let Some(event) = gate.process(buffer, &mut output) {
+                events.push((position, event));
+            }
+        }
+        (events, output)
+    }
+
+    fn started(input: &[f32]) -> bool {
+        run(input).0.iter().any(|&(_, ref event)| *event == VadEvent::SpeechStarted)
+    }
+
+    #[test]
+    fn silence_never_opens() {
+        let (events, output) = run(&vec![0.0; frames(3.0)]);
+        assert!(events.is_empty());
+        assert!(output.is_empty());
+
+        let (events, output) = run(&room(3.0));
+        assert!(events.is_empty());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn tone_burst_starts_and_ends() {
+        let input = concat(&[room(0.5), tone(440.0, 0.5, -20.0), room(2.0)]);
+        let (events, output) = run(&input);
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].1, VadEvent::SpeechStarted);
+        assert_eq!(events[1].1, VadEvent::SpeechEnded);
+
+        // Five windows of tone to start, and a second of trailing silence to end.
+        assert!(events[0].0 >= frames(0.55) && events[0].0 < frames(0.55) + 333);
+        assert!(events[1].0 >= frames(2.0) && events[1].0 < frames(2.0) + 333);
+
+        // The pre-roll reaches back 0.3 s before the tone began, and capture runs to the end of
+        // the trailing silence.
+        assert!(output[..] == input[frames(0.2)..frames(2.0)]);
+    }
+
+    #[test]
+    fn pre_roll_keeps_audio_from_before_the_start() {
+        let input = concat(&[room(1.0), tone(300.0, 0.2, -10.0), room(1.5)]);
+        let (_, output) = run(&input);
+
+        // Exactly the pre-roll of room noise, then the tone itself.
+        assert!(output[..frames(0.3)] == input[frames(0.7)..frames(1.0)]);
+        assert!(output[frames(0.3)..frames(0.5)] == input[frames(1.0)..frames(1.2)]);
+    }
+
+    #[test]
+    fn pauses_shorter_than_the_trailing_silence_keep_going() {
+        let input = concat(&[tone(440.0, 0.3, -20.0), room(0.8), tone(440.0, 0.3, -20.0), room(1.2)]);
+        let (events, output) = run(&input);
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].1, VadEvent::SpeechEnded);
+        assert_eq!(output.len(), frames(0.3 + 0.8 + 0.3 + 1.0));
+    }
+
+    #[test]
+    fn stays_closed_once_ended() {
+        let input = concat(&[tone(440.0, 0.3, -20.0), room(1.2), tone(440.0, 0.5, -20.0), room(1.2)]);
+        let (events, output) = run(&input);
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(output.len(), frames(0.3 + 1.0));
+    }
+
+    #[test]
+    fn clicks_are_too_short() {
+        let input = concat(&[room(0.5), tone(1000.0, 0.02, -6.0), room(1.0), tone(1000.0, 0.03, -6.0), room(1.0)]);
+        assert!(!started(&input));
+    }
+
+    #[test]
+    fn deep_voices_are_not_hum() {
+        for &frequency in &[78.0, 85.0, 100.0, 120.0] {
+            let input = concat(&[room(0.5), tone(frequency, 0.5, -20.0), room(0.5)]);
+            assert!(started(&input), "{} Hz was taken for hum", frequency);
+        }
+    }
+
+    #[test]
+    fn mains_hum_is_ignored() {
+        for &frequency in &[50.0, 60.0] {
+            assert!(!started(&tone(frequency, 3.0, -10.0)), "{} Hz hum was taken for speech", frequency);
+        }
+    }
+
+    #[test]
+    fn quiet_fricatives_start_but_background_noise_does_not() {
+        // Below the -40 dBFS threshold, but hissing like an "s".
+        assert!(started(&concat(&[room(0.5), noise(0.3, -46.0, 1), room(0.5)])));
+        assert!(!started(&noise(3.0, -55.0, 2)));
+    }
+
+    #[test]
+    fn stereo_frames_are_gated_whole() {
+        let mono = concat(&[room(0.5), tone(440.0, 0.5, -20.0), room(2.0)]);
+        let stereo: Vec<f32> = mono.iter().flat_map(|&sample| vec![sample, sample * 0.5]).collect();
+
+        let mut gate = Gate::new(&VadConfig::default(), 2, RATE);
+        let mut output = Vec::new();
+        for buffer in stereo.chunks(334) {
+            gate.process(buffer, &mut output);
+        }
+
+        assert!(output[..] == stereo[2 * frames(0.2)..2 * frames(2.0)]);
+    }
+}